@@ -19,7 +19,7 @@ impl<K, V> BiMap<K, V> {
         }
     }
 
-    pub fn iter(&self) -> Iter<K, V> {
+    pub fn iter(&self) -> Iter<'_, K, V> {
         self.kv.iter()
     }
 
@@ -34,6 +34,10 @@ where
     V: Eq + Hash + Clone,
 {
     pub fn insert(&mut self, key: K, value: V) {
+        self.remove_by_key(&key);
+
+        self.remove_by_value(&value);
+
         self.vk.insert(value.clone(), key.clone());
 
         self.kv.insert(key, value);
@@ -43,18 +47,22 @@ where
 impl<K, V> BiMap<K, V>
 where
     K: Eq + Hash,
+    V: Eq + Hash,
 {
     pub fn remove_by_key(&mut self, key: &K) -> Option<V> {
-        self.kv.remove(key)
+        let val = self.kv.remove(key)?;
+
+        self.vk.remove(&val);
+
+        Some(val)
     }
-}
 
-impl<K, V> BiMap<K, V>
-where
-    V: Eq + Hash,
-{
     pub fn remove_by_value(&mut self, val: &V) -> Option<K> {
-        self.vk.remove(val)
+        let key = self.vk.remove(val)?;
+
+        self.kv.remove(&key);
+
+        Some(key)
     }
 }
 
@@ -76,20 +84,52 @@ where
     }
 }
 
-impl<K, V> BiMap<K, V>
-where
-    K: Eq + Hash,
-{
-    pub fn get_mut_by_key(&mut self, key: &K) -> Option<&mut V> {
-        self.kv.get_mut(key)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removing_by_key_forgets_the_value() {
+        let mut map = BiMap::new();
+
+        map.insert("alice", 1);
+
+        assert_eq!(map.remove_by_key(&"alice"), Some(1));
+
+        assert_eq!(map.get_by_val(&1), None);
+
+        assert_eq!(map.remove_by_value(&1), None);
     }
-}
 
-impl<K, V> BiMap<K, V>
-where
-    V: Eq + Hash,
-{
-    pub fn get_mut_by_val(&mut self, val: &V) -> Option<&mut K> {
-        self.vk.get_mut(val)
+    #[test]
+    fn removing_by_value_forgets_the_key() {
+        let mut map = BiMap::new();
+
+        map.insert("alice", 1);
+
+        assert_eq!(map.remove_by_value(&1), Some("alice"));
+
+        assert_eq!(map.get_by_key(&"alice"), None);
+
+        assert_eq!(map.remove_by_key(&"alice"), None);
+    }
+
+    #[test]
+    fn inserting_replaces_both_sides() {
+        let mut map = BiMap::new();
+
+        map.insert("alice", 1);
+
+        map.insert("alice", 2);
+
+        assert_eq!(map.get_by_val(&1), None);
+
+        map.insert("bob", 2);
+
+        assert_eq!(map.get_by_key(&"alice"), None);
+
+        assert_eq!(map.get_by_val(&2), Some(&"bob"));
+
+        assert_eq!(map.iter().count(), 1);
     }
 }
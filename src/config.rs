@@ -0,0 +1,502 @@
+use std::{
+    collections::HashMap, error::Error, fmt, fs, io, net::SocketAddr, str::FromStr, time::Duration,
+};
+
+use jsonwebtoken::Algorithm;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    connection::OutboundLimits,
+    env::{self, API_ORIGIN},
+    tick::DEFAULT_TICK_RATE,
+};
+
+pub const CONFIG_PATH: &str = "CONFIG_PATH";
+
+pub const DEFAULT_CONFIG_PATH: &str = "config.yaml";
+
+/// Every setting of the server.
+///
+/// Loaded from a YAML file, where every field is optional, and then
+/// overridden by the environment variable named after the field in upper
+/// case, like `TICK_RATE` for `tick_rate`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub tcp_addr: SocketAddr,
+    pub ws_addr: SocketAddr,
+    pub udp_addr: SocketAddr,
+    pub auth_provider: AuthProviderKind,
+    pub api_origin: String,
+    pub auth_path: String,
+    pub auth_timeout_ms: u64,
+    pub jwt_public_key_path: String,
+    pub jwt_algorithm: Algorithm,
+    pub jwt_issuer: String,
+    pub mock_tokens: HashMap<String, String>,
+    pub read_buffer_size: usize,
+    pub udp_mtu: usize,
+    pub tick_rate: u32,
+    pub outbound_high_water: usize,
+    pub outbound_hard_limit: usize,
+    pub outbound_grace_ms: u64,
+    pub shutdown_timeout_ms: u64,
+    pub tcp_hello_timeout_ms: u64,
+    pub udp_hello_timeout_ms: u64,
+    pub heartbeat_interval_ms: u64,
+    pub heartbeat_max_missed: u32,
+    pub resume_grace_ms: u64,
+    pub duplicate_login: DuplicateLoginPolicy,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        let outbound = OutboundLimits::default();
+
+        ServerConfig {
+            tcp_addr: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            ws_addr: SocketAddr::from(([0, 0, 0, 0], 3001)),
+            udp_addr: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            auth_provider: AuthProviderKind::Http,
+            api_origin: String::new(),
+            auth_path: String::from("auth"),
+            auth_timeout_ms: 5_000,
+            jwt_public_key_path: String::new(),
+            jwt_algorithm: Algorithm::RS256,
+            jwt_issuer: String::new(),
+            mock_tokens: HashMap::new(),
+            read_buffer_size: 4096,
+            udp_mtu: 1200,
+            tick_rate: DEFAULT_TICK_RATE,
+            outbound_high_water: outbound.high_water,
+            outbound_hard_limit: outbound.hard_limit,
+            outbound_grace_ms: outbound.grace.as_millis() as u64,
+            shutdown_timeout_ms: 10_000,
+            tcp_hello_timeout_ms: 10_000,
+            udp_hello_timeout_ms: 10_000,
+            heartbeat_interval_ms: 1_000,
+            heartbeat_max_missed: 5,
+            resume_grace_ms: 30_000,
+            duplicate_login: DuplicateLoginPolicy::Kick,
+        }
+    }
+}
+
+/// Where tokens are checked, see [`crate::auth`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthProviderKind {
+    Http,
+    Jwt,
+    Mock,
+}
+
+impl FromStr for AuthProviderKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http" => Ok(AuthProviderKind::Http),
+            "jwt" => Ok(AuthProviderKind::Jwt),
+            "mock" => Ok(AuthProviderKind::Mock),
+            _ => Err(String::from("expected one of http, jwt or mock")),
+        }
+    }
+}
+
+/// What to do when a player authenticates while already connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateLoginPolicy {
+    /// Disconnect the old connection and let the new one take over.
+    Kick,
+    /// Disconnect the new connection and keep the old one.
+    Reject,
+}
+
+impl FromStr for DuplicateLoginPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kick" => Ok(DuplicateLoginPolicy::Kick),
+            "reject" => Ok(DuplicateLoginPolicy::Reject),
+            _ => Err(String::from("expected one of kick or reject")),
+        }
+    }
+}
+
+/// Everything wrong with a config, reported at once.
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "invalid config:")?;
+
+        for problem in &self.0 {
+            writeln!(f, "  - {problem}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Error for ConfigError {}
+
+impl ServerConfig {
+    /// Loads the file at `CONFIG_PATH`, or `config.yaml` if it exists, then
+    /// applies the environment and validates the result.
+    pub fn load() -> Result<Self, ConfigError> {
+        let path = env::get(CONFIG_PATH);
+
+        let text = match fs::read_to_string(path.as_deref().unwrap_or(DEFAULT_CONFIG_PATH)) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && path.is_none() => String::new(),
+            Err(e) => {
+                let path = path.as_deref().unwrap_or(DEFAULT_CONFIG_PATH);
+
+                return Err(ConfigError(vec![format!("{path}: {e}")]));
+            }
+        };
+
+        let mut config = ServerConfig::from_yaml(&text)?;
+
+        let mut problems = config.apply_env();
+
+        if let Err(ConfigError(invalid)) = config.validate() {
+            problems.extend(invalid);
+        }
+
+        match problems.is_empty() {
+            true => Ok(config),
+            false => Err(ConfigError(problems)),
+        }
+    }
+
+    /// Parses a config file without applying the environment. An empty file
+    /// is the default config.
+    pub fn from_yaml(text: &str) -> Result<Self, ConfigError> {
+        match text.trim().is_empty() {
+            true => Ok(ServerConfig::default()),
+            false => serde_yaml::from_str(text).map_err(|e| ConfigError(vec![e.to_string()])),
+        }
+    }
+
+    fn apply_env(&mut self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        let p = &mut problems;
+
+        override_from_env("TCP_ADDR", &mut self.tcp_addr, p);
+
+        override_from_env("WS_ADDR", &mut self.ws_addr, p);
+
+        override_from_env("UDP_ADDR", &mut self.udp_addr, p);
+
+        override_from_env("AUTH_PROVIDER", &mut self.auth_provider, p);
+
+        override_from_env(API_ORIGIN, &mut self.api_origin, p);
+
+        override_from_env("AUTH_PATH", &mut self.auth_path, p);
+
+        override_from_env("AUTH_TIMEOUT_MS", &mut self.auth_timeout_ms, p);
+
+        override_from_env("JWT_PUBLIC_KEY_PATH", &mut self.jwt_public_key_path, p);
+
+        override_from_env("JWT_ALGORITHM", &mut self.jwt_algorithm, p);
+
+        override_from_env("JWT_ISSUER", &mut self.jwt_issuer, p);
+
+        override_from_env("READ_BUFFER_SIZE", &mut self.read_buffer_size, p);
+
+        override_from_env("UDP_MTU", &mut self.udp_mtu, p);
+
+        override_from_env("TICK_RATE", &mut self.tick_rate, p);
+
+        override_from_env("OUTBOUND_HIGH_WATER", &mut self.outbound_high_water, p);
+
+        override_from_env("OUTBOUND_HARD_LIMIT", &mut self.outbound_hard_limit, p);
+
+        override_from_env("OUTBOUND_GRACE_MS", &mut self.outbound_grace_ms, p);
+
+        override_from_env("SHUTDOWN_TIMEOUT_MS", &mut self.shutdown_timeout_ms, p);
+
+        override_from_env("TCP_HELLO_TIMEOUT_MS", &mut self.tcp_hello_timeout_ms, p);
+
+        override_from_env("UDP_HELLO_TIMEOUT_MS", &mut self.udp_hello_timeout_ms, p);
+
+        override_from_env("HEARTBEAT_INTERVAL_MS", &mut self.heartbeat_interval_ms, p);
+
+        override_from_env("HEARTBEAT_MAX_MISSED", &mut self.heartbeat_max_missed, p);
+
+        override_from_env("RESUME_GRACE_MS", &mut self.resume_grace_ms, p);
+
+        override_from_env("DUPLICATE_LOGIN", &mut self.duplicate_login, p);
+
+        problems
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        match self.auth_provider {
+            AuthProviderKind::Http => {
+                if !self.api_origin.starts_with("http://")
+                    && !self.api_origin.starts_with("https://")
+                {
+                    problems.push(format!(
+                        "api_origin must be an http(s) origin, got {:?}",
+                        self.api_origin
+                    ));
+                }
+
+                if self.auth_path.is_empty() {
+                    problems.push(String::from("auth_path must not be empty"));
+                }
+
+                if self.auth_timeout_ms == 0 {
+                    problems.push(String::from("auth_timeout_ms must be positive"));
+                }
+            }
+            AuthProviderKind::Jwt => {
+                if self.jwt_public_key_path.is_empty() {
+                    problems.push(String::from("jwt_public_key_path must be set"));
+                }
+
+                if matches!(
+                    self.jwt_algorithm,
+                    Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+                ) {
+                    problems.push(format!(
+                        "jwt_algorithm must use a public key, got {:?}",
+                        self.jwt_algorithm
+                    ));
+                }
+            }
+            AuthProviderKind::Mock => {}
+        }
+
+        if self.read_buffer_size == 0 {
+            problems.push(String::from("read_buffer_size must be positive"));
+        }
+
+        if !(508..=65507).contains(&self.udp_mtu) {
+            problems.push(format!(
+                "udp_mtu must be between 508 and 65507, got {}",
+                self.udp_mtu
+            ));
+        }
+
+        if !(1..=1000).contains(&self.tick_rate) {
+            problems.push(format!(
+                "tick_rate must be between 1 and 1000, got {}",
+                self.tick_rate
+            ));
+        }
+
+        if self.tcp_hello_timeout_ms == 0 || self.udp_hello_timeout_ms == 0 {
+            problems.push(String::from("handshake timeouts must be positive"));
+        }
+
+        if self.heartbeat_interval_ms == 0 || self.heartbeat_max_missed == 0 {
+            problems.push(String::from(
+                "heartbeat_interval_ms and heartbeat_max_missed must be positive",
+            ));
+        }
+
+        if self.outbound_high_water > self.outbound_hard_limit {
+            problems.push(format!(
+                "outbound_high_water must not exceed outbound_hard_limit, got {} > {}",
+                self.outbound_high_water, self.outbound_hard_limit
+            ));
+        }
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(ConfigError(problems)),
+        }
+    }
+
+    pub fn auth_endpoint(&self) -> String {
+        format!(
+            "{}/{}",
+            self.api_origin.trim_end_matches('/'),
+            self.auth_path
+        )
+    }
+
+    /// How long the API has to answer a token check.
+    pub fn auth_timeout(&self) -> Duration {
+        Duration::from_millis(self.auth_timeout_ms)
+    }
+
+    pub fn outbound_limits(&self) -> OutboundLimits {
+        OutboundLimits {
+            high_water: self.outbound_high_water,
+            hard_limit: self.outbound_hard_limit,
+            grace: Duration::from_millis(self.outbound_grace_ms),
+        }
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_millis(self.shutdown_timeout_ms)
+    }
+
+    /// How long an accepted connection has to finish its TCP handshake.
+    pub fn tcp_hello_timeout(&self) -> Duration {
+        Duration::from_millis(self.tcp_hello_timeout_ms)
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_millis(self.heartbeat_interval_ms)
+    }
+
+    /// How long a player has to bind its UDP address after the TCP
+    /// handshake. WebSocket players are exempt.
+    pub fn udp_hello_timeout(&self) -> Duration {
+        Duration::from_millis(self.udp_hello_timeout_ms)
+    }
+
+    /// How long a dropped player is kept around for its client to resume.
+    /// Zero drops players right away.
+    pub fn resume_grace(&self) -> Duration {
+        Duration::from_millis(self.resume_grace_ms)
+    }
+}
+
+fn override_from_env<T>(key: &str, value: &mut T, problems: &mut Vec<String>)
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    if let Some(text) = env::get(key) {
+        match text.parse() {
+            Ok(parsed) => *value = parsed,
+            Err(e) => problems.push(format!("{key}: {e}, got {text:?}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mock() -> ServerConfig {
+        ServerConfig {
+            auth_provider: AuthProviderKind::Mock,
+            ..ServerConfig::default()
+        }
+    }
+
+    #[test]
+    fn example_config_is_valid() {
+        let config = ServerConfig::from_yaml(include_str!("../config.example.yaml")).unwrap();
+
+        assert_eq!(config.auth_provider, AuthProviderKind::Http);
+
+        assert_eq!(config.udp_addr, SocketAddr::from(([0, 0, 0, 0], 3000)));
+
+        assert_eq!(config.duplicate_login, DuplicateLoginPolicy::Kick);
+
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn missing_fields_take_their_default() {
+        let config = ServerConfig::from_yaml("auth_provider: mock\ntick_rate: 30\n").unwrap();
+
+        assert_eq!(config.auth_provider, AuthProviderKind::Mock);
+
+        assert_eq!(config.tick_rate, 30);
+
+        assert_eq!(config.udp_mtu, ServerConfig::default().udp_mtu);
+
+        let config = ServerConfig::from_yaml(" \n").unwrap();
+
+        assert_eq!(config.tcp_addr, ServerConfig::default().tcp_addr);
+    }
+
+    #[test]
+    fn unknown_or_malformed_fields_are_refused() {
+        assert!(ServerConfig::from_yaml("tick_rat: 30\n").is_err());
+
+        assert!(ServerConfig::from_yaml("tick_rate: fast\n").is_err());
+
+        assert!(ServerConfig::from_yaml("duplicate_login: ignore\n").is_err());
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        let config = ServerConfig {
+            udp_mtu: 100,
+            tick_rate: 0,
+            outbound_high_water: 2,
+            outbound_hard_limit: 1,
+            ..mock()
+        };
+
+        let ConfigError(problems) = config.validate().unwrap_err();
+
+        assert_eq!(problems.len(), 3);
+
+        mock().validate().unwrap();
+    }
+
+    #[test]
+    fn validate_checks_the_selected_provider() {
+        let http = ServerConfig {
+            api_origin: String::from("localhost:8080"),
+            ..ServerConfig::default()
+        };
+
+        assert!(http.validate().is_err());
+
+        let jwt = ServerConfig {
+            auth_provider: AuthProviderKind::Jwt,
+            jwt_public_key_path: String::from("auth.pem"),
+            jwt_algorithm: Algorithm::HS256,
+            ..ServerConfig::default()
+        };
+
+        assert!(jwt.validate().is_err());
+
+        let jwt = ServerConfig {
+            jwt_algorithm: Algorithm::ES256,
+            ..jwt
+        };
+
+        jwt.validate().unwrap();
+    }
+
+    // The only test touching these variables, as the environment is shared
+    // by every test.
+    #[test]
+    fn environment_overrides_the_file() {
+        std::env::set_var("TICK_RATE", "60");
+
+        std::env::set_var("DUPLICATE_LOGIN", "reject");
+
+        std::env::set_var("UDP_MTU", "large");
+
+        let mut config = mock();
+
+        let problems = config.apply_env();
+
+        std::env::remove_var("TICK_RATE");
+
+        std::env::remove_var("DUPLICATE_LOGIN");
+
+        std::env::remove_var("UDP_MTU");
+
+        assert_eq!(config.tick_rate, 60);
+
+        assert_eq!(config.duplicate_login, DuplicateLoginPolicy::Reject);
+
+        assert_eq!(config.udp_mtu, mock().udp_mtu);
+
+        assert_eq!(problems.len(), 1);
+
+        assert!(problems[0].starts_with("UDP_MTU"));
+    }
+}
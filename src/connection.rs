@@ -0,0 +1,379 @@
+use std::{
+    error::Error,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::mpsc,
+    task::JoinHandle,
+    time,
+};
+
+use crate::{
+    incoming_packet::Incoming,
+    net::{wrap_tcp_packet, FrameDecoder},
+    websocket,
+};
+
+pub type ConnectionId = u64;
+
+/// How long a connection dropped after a farewell packet may take to flush
+/// it before its socket is closed anyway.
+pub const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What a reader task forwards to the game loop: a decoded packet, or the
+/// error that ended the connection.
+pub type Inbound = (ConnectionId, Result<Incoming, Box<dyn Error + Sync + Send>>);
+
+/// Limits on how many bytes may wait in a connection's outbound queue.
+///
+/// A connection above `high_water` is congested and is dropped if it stays
+/// congested for longer than `grace`. Going above `hard_limit` drops it at
+/// once.
+#[derive(Debug, Clone)]
+pub struct OutboundLimits {
+    pub high_water: usize,
+    pub hard_limit: usize,
+    pub grace: Duration,
+}
+
+impl Default for OutboundLimits {
+    fn default() -> Self {
+        OutboundLimits {
+            high_water: 64 * 1024,
+            hard_limit: 1024 * 1024,
+            grace: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The game loop's handle on a client socket.
+///
+/// A reader task decodes incoming frames and forwards them as [`Inbound`],
+/// and a writer task drains the outbound queue into the socket. The loop
+/// only tracks how many bytes are still queued.
+///
+/// Dropping the connection stops both tasks and closes the socket, whatever
+/// is still queued. See [`Connection::release`] to let a farewell through.
+pub struct Connection {
+    pub id: ConnectionId,
+    outbound: mpsc::UnboundedSender<Vec<u8>>,
+    queued: Arc<AtomicUsize>,
+    compression: Arc<AtomicBool>,
+    websocket: bool,
+    reader: Option<JoinHandle<()>>,
+    writer: JoinHandle<()>,
+    congested_since: Option<time::Instant>,
+    farewell: bool,
+}
+
+impl Connection {
+    pub fn tcp(
+        id: ConnectionId,
+        stream: TcpStream,
+        inbound: mpsc::Sender<Inbound>,
+        buffer_size: usize,
+    ) -> Self {
+        let (read_half, write_half) = stream.into_split();
+
+        let (outbound, rx) = mpsc::unbounded_channel();
+
+        let queued = Arc::new(AtomicUsize::new(0));
+
+        let compression = Arc::new(AtomicBool::new(false));
+
+        let reader = tokio::spawn(read(
+            id,
+            read_half,
+            buffer_size,
+            compression.clone(),
+            inbound,
+        ));
+
+        let writer = tokio::spawn(write(write_half, rx, queued.clone()));
+
+        Connection {
+            id,
+            outbound,
+            queued,
+            compression,
+            websocket: false,
+            reader: Some(reader),
+            writer,
+            congested_since: None,
+            farewell: false,
+        }
+    }
+
+    pub fn websocket(id: ConnectionId, stream: TcpStream, inbound: mpsc::Sender<Inbound>) -> Self {
+        let (outbound, rx) = mpsc::unbounded_channel();
+
+        let queued = Arc::new(AtomicUsize::new(0));
+
+        let writer = tokio::spawn(websocket::bridge(id, stream, inbound, rx, queued.clone()));
+
+        Connection {
+            id,
+            outbound,
+            queued,
+            compression: Arc::new(AtomicBool::new(false)),
+            websocket: true,
+            reader: None,
+            writer,
+            congested_since: None,
+            farewell: false,
+        }
+    }
+
+    pub fn is_websocket(&self) -> bool {
+        self.websocket
+    }
+
+    pub fn is_congested(&self) -> bool {
+        self.congested_since.is_some()
+    }
+
+    /// Compresses frames in both directions from now on.
+    ///
+    /// WebSocket connections are never compressed. Each packet travels as one
+    /// binary message without the TCP length header, so there is nowhere to
+    /// mark a compressed frame, and permessage-deflate is not negotiated
+    /// either.
+    pub fn enable_compression(&mut self) -> bool {
+        if self.websocket {
+            return false;
+        }
+
+        self.compression.store(true, Ordering::Relaxed);
+
+        true
+    }
+
+    /// Queues `buf` for the writer, framed for TCP. WebSocket messages carry
+    /// the packet as is.
+    pub fn enqueue_packet(&mut self, buf: &[u8]) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let buf = match self.websocket {
+            true => buf.to_vec(),
+            false => wrap_tcp_packet(buf, self.compression.load(Ordering::Relaxed))?,
+        };
+
+        self.queued.fetch_add(buf.len(), Ordering::Relaxed);
+
+        self.outbound
+            .send(buf)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+
+        Ok(())
+    }
+
+    /// Queues the last packet the client should get before it is dropped,
+    /// like a `Disconnect` or a `Reject`.
+    pub fn enqueue_farewell(&mut self, buf: &[u8]) -> Result<(), Box<dyn Error + Sync + Send>> {
+        self.enqueue_packet(buf)?;
+
+        self.farewell = true;
+
+        Ok(())
+    }
+
+    /// Lets go of a connection the loop no longer tracks.
+    ///
+    /// A connection with a farewell queued keeps its writer for up to
+    /// [`LINGER_TIMEOUT`] to flush it. Any other is closed right away.
+    pub fn release(self) {
+        if self.farewell {
+            tokio::spawn(time::timeout(LINGER_TIMEOUT, self.close()));
+        }
+    }
+
+    /// Stops reading and waits until everything queued has been written.
+    pub async fn close(mut self) {
+        if let Some(reader) = self.reader.take() {
+            reader.abort();
+        }
+
+        let (outbound, _) = mpsc::unbounded_channel();
+
+        drop(std::mem::replace(&mut self.outbound, outbound));
+
+        let _ = (&mut self.writer).await;
+    }
+
+    pub fn check_backpressure(
+        &mut self,
+        limits: &OutboundLimits,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let len = self.queued.load(Ordering::Relaxed);
+
+        if len > limits.hard_limit {
+            return Err(format!("outbound queue over hard limit, {len} bytes").into());
+        }
+
+        if len <= limits.high_water {
+            self.congested_since = None;
+
+            return Ok(());
+        }
+
+        let since = *self.congested_since.get_or_insert_with(time::Instant::now);
+
+        if since.elapsed() >= limits.grace {
+            return Err(format!("outbound queue stayed over high-water mark, {len} bytes").into());
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(reader) = &self.reader {
+            reader.abort();
+        }
+
+        self.writer.abort();
+    }
+}
+
+async fn read(
+    id: ConnectionId,
+    mut stream: OwnedReadHalf,
+    buffer_size: usize,
+    compression: Arc<AtomicBool>,
+    inbound: mpsc::Sender<Inbound>,
+) {
+    let mut buf = vec![0; buffer_size];
+
+    let mut decoder = FrameDecoder::new();
+
+    loop {
+        let n = match stream.read(&mut buf).await {
+            Ok(0) => {
+                let e = io::Error::from(io::ErrorKind::UnexpectedEof);
+
+                let _ = inbound.send((id, Err(e.into()))).await;
+
+                return;
+            }
+            Ok(n) => n,
+            Err(e) => {
+                let _ = inbound.send((id, Err(e.into()))).await;
+
+                return;
+            }
+        };
+
+        decoder.extend(&buf[..n]);
+
+        if compression.load(Ordering::Relaxed) {
+            decoder.enable_compression();
+        }
+
+        while let Some(frame) = decoder.next_frame() {
+            let incoming = frame.and_then(|buf| Incoming::deserialize_tcp(&buf));
+
+            let failed = incoming.is_err();
+
+            if inbound.send((id, incoming)).await.is_err() || failed {
+                return;
+            }
+        }
+    }
+}
+
+async fn write(
+    mut stream: OwnedWriteHalf,
+    mut outbound: mpsc::UnboundedReceiver<Vec<u8>>,
+    queued: Arc<AtomicUsize>,
+) {
+    while let Some(buf) = outbound.recv().await {
+        if let Err(e) = stream.write_all(&buf).await {
+            eprintln!("tcp write failed for {e}");
+
+            return;
+        }
+
+        queued.fetch_sub(buf.len(), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const PACKET_LEN: usize = 60_000;
+
+    /// A connection and the client end of its socket.
+    async fn pair() -> (Connection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let (server, _) = listener.accept().await.unwrap();
+
+        let (inbound, _) = mpsc::channel(1);
+
+        (Connection::tcp(0, server, inbound, 4096), client)
+    }
+
+    /// Reads until the server closes the socket, returning how many bytes
+    /// arrived.
+    async fn read_to_close(client: &mut TcpStream) -> usize {
+        let mut buf = vec![0; 64 * 1024];
+
+        let mut total = 0;
+
+        loop {
+            let read = time::timeout(Duration::from_secs(5), client.read(&mut buf)).await;
+
+            match read.expect("socket was never closed") {
+                Ok(0) | Err(_) => return total,
+                Ok(n) => total += n,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn dropping_closes_the_socket_of_a_stalled_client() {
+        let (mut connection, mut client) = pair().await;
+
+        let packets = 256;
+
+        for _ in 0..packets {
+            connection.enqueue_packet(&[0; PACKET_LEN]).unwrap();
+        }
+
+        time::sleep(Duration::from_millis(100)).await;
+
+        assert!(connection.queued.load(Ordering::Relaxed) > 0);
+
+        drop(connection);
+
+        assert!(read_to_close(&mut client).await < packets * PACKET_LEN);
+    }
+
+    #[tokio::test]
+    async fn released_farewell_is_flushed_before_closing() {
+        let (mut connection, mut client) = pair().await;
+
+        connection.enqueue_farewell(&[7; 16]).unwrap();
+
+        connection.release();
+
+        assert_eq!(read_to_close(&mut client).await, 2 + 16);
+    }
+}
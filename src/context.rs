@@ -1,30 +1,181 @@
-use std::{
-    collections::{BinaryHeap, HashMap},
-    net::SocketAddr,
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    sync::mpsc,
+    time,
 };
 
-use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use crate::{
+    auth::AuthProvider,
+    collection::BiMap,
+    config::ServerConfig,
+    connection::{Connection, ConnectionId, Inbound},
+    handler::HandlerRegistry,
+    handshake_handler, heartbeat_handler,
+    incoming_packet::Incoming,
+    job::Job,
+    job_handler::send_snapshots,
+    player::Player,
+    player_handler,
+    protocol::Batch,
+    reliable::Channel,
+    schedule::Schedule,
+    schedule_queue::{ScheduleHandle, ScheduleQueue},
+    session::Session,
+    snapshot::SnapshotHistory,
+    tick::{self, TickHooks},
+};
 
-use crate::{collection::BiMap, job::Job, schedule::Schedule};
+/// How many decoded packets the reader tasks may queue up before they wait
+/// for the game loop.
+const INCOMING_CAPACITY: usize = 1024;
 
 pub struct Context {
-    pub tcp_listener: TcpListener,
-    pub waitings: Vec<TcpStream>,
-    pub tcp_streams: HashMap<String, TcpStream>,
+    pub config: ServerConfig,
+    pub auth_provider: Arc<dyn AuthProvider>,
+    /// `None` once the server stopped accepting connections.
+    pub tcp_listener: Option<TcpListener>,
+    pub ws_listener: Option<TcpListener>,
+    pub connections: HashMap<ConnectionId, Connection>,
+    /// The connection speaking for each player.
+    pub tcp_ids: BiMap<String, ConnectionId>,
+    pub next_connection_id: ConnectionId,
+    pub incoming_sender: mpsc::Sender<Inbound>,
+    pub incoming_receiver: mpsc::Receiver<Inbound>,
+    pub pending_auths: HashMap<ConnectionId, Vec<Incoming>>,
+    pub handshake_deadlines: HashMap<ConnectionId, ScheduleHandle>,
+    pub heartbeats: HashMap<ConnectionId, ScheduleHandle>,
+    pub suspended: HashMap<String, ScheduleHandle>,
+    pub job_sender: mpsc::UnboundedSender<Job>,
+    pub job_receiver: mpsc::UnboundedReceiver<Job>,
     pub udp_socket: UdpSocket,
+    /// Where the datagrams of each player go and come from, once bound.
     pub udp_addrs: BiMap<String, SocketAddr>,
-    pub schedule_queue: BinaryHeap<Schedule<Job>>,
+    pub udp_channels: HashMap<String, Channel>,
+    pub udp_batches: HashMap<String, Batch>,
+    pub sessions: HashMap<u32, Session>,
+    pub session_ids: BiMap<String, u32>,
+    pub next_session_id: u32,
+    pub schedule_queue: ScheduleQueue<Job>,
+    /// The world state of every player, see `Player`.
+    pub players: HashMap<String, Player>,
+    pub snapshots: SnapshotHistory,
+    pub tick: u32,
+    pub tick_hooks: TickHooks,
+    pub handlers: HandlerRegistry,
 }
 
 impl Context {
-    pub fn new(tcp_listener: TcpListener, udp_socket: UdpSocket) -> Self {
+    pub fn new(
+        config: ServerConfig,
+        auth_provider: Arc<dyn AuthProvider>,
+        tcp_listener: TcpListener,
+        ws_listener: TcpListener,
+        udp_socket: UdpSocket,
+    ) -> Self {
+        let mut schedule_queue = ScheduleQueue::new();
+
+        let deadline = time::Instant::now() + tick::interval(config.tick_rate);
+
+        schedule_queue.push(Schedule::new(Job::Tick(deadline), deadline));
+
+        let (incoming_sender, incoming_receiver) = mpsc::channel(INCOMING_CAPACITY);
+
+        let (job_sender, job_receiver) = mpsc::unbounded_channel();
+
+        let mut tick_hooks = TickHooks::default();
+
+        tick_hooks.snapshots.push(send_snapshots);
+
+        let mut handlers = HandlerRegistry::new();
+
+        handshake_handler::register(&mut handlers);
+
+        player_handler::register(&mut handlers);
+
+        heartbeat_handler::register(&mut handlers);
+
         Context {
-            tcp_listener,
-            waitings: Vec::new(),
-            tcp_streams: HashMap::new(),
+            tcp_listener: Some(tcp_listener),
+            ws_listener: Some(ws_listener),
+            connections: HashMap::new(),
+            tcp_ids: BiMap::new(),
+            next_connection_id: 0,
+            incoming_sender,
+            incoming_receiver,
+            pending_auths: HashMap::new(),
+            handshake_deadlines: HashMap::new(),
+            heartbeats: HashMap::new(),
+            suspended: HashMap::new(),
+            job_sender,
+            job_receiver,
             udp_socket,
             udp_addrs: BiMap::new(),
-            schedule_queue: BinaryHeap::new(),
+            udp_channels: HashMap::new(),
+            udp_batches: HashMap::new(),
+            sessions: HashMap::new(),
+            session_ids: BiMap::new(),
+            next_session_id: 0,
+            schedule_queue,
+            players: HashMap::new(),
+            snapshots: SnapshotHistory::new(),
+            tick: 0,
+            tick_hooks,
+            handlers,
+            config,
+            auth_provider,
+        }
+    }
+
+    pub fn next_connection_id(&mut self) -> ConnectionId {
+        let id = self.next_connection_id;
+
+        self.next_connection_id += 1;
+
+        id
+    }
+
+    /// Schedules `job` to time out the current handshake stage of a
+    /// connection, replacing the deadline of the previous stage.
+    pub fn set_handshake_deadline(
+        &mut self,
+        connection_id: ConnectionId,
+        job: Job,
+        timeout: Duration,
+    ) {
+        self.clear_handshake_deadline(connection_id);
+
+        let handle = self
+            .schedule_queue
+            .push(Schedule::new(job, time::Instant::now() + timeout));
+
+        self.handshake_deadlines.insert(connection_id, handle);
+    }
+
+    pub fn clear_handshake_deadline(&mut self, connection_id: ConnectionId) {
+        if let Some(handle) = self.handshake_deadlines.remove(&connection_id) {
+            self.schedule_queue.cancel(handle);
         }
     }
+
+    pub fn session(&self, id: &str) -> Option<&Session> {
+        self.session_ids
+            .get_by_key(&id.to_owned())
+            .and_then(|session_id| self.sessions.get(session_id))
+    }
+
+    pub fn session_mut(&mut self, id: &str) -> Option<&mut Session> {
+        self.session_ids
+            .get_by_key(&id.to_owned())
+            .and_then(|session_id| self.sessions.get_mut(session_id))
+    }
+
+    pub fn player(&self, id: &str) -> Option<&Player> {
+        self.players.get(id)
+    }
+
+    pub fn player_mut(&mut self, id: &str) -> Option<&mut Player> {
+        self.players.get_mut(id)
+    }
 }
@@ -0,0 +1,247 @@
+use std::{collections::HashMap, error::Error, net::SocketAddr, sync::Arc};
+
+use crate::{
+    connection::ConnectionId,
+    incoming_packet::{Incoming, IncomingKind},
+    Context,
+};
+
+/// The transport a packet arrived over. WebSocket clients count as TCP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+/// Who sent a packet, as far as the server trusts it.
+#[derive(Debug, Clone)]
+pub enum Peer {
+    /// A connection that has not finished its TCP handshake.
+    Waiting(ConnectionId),
+    /// A datagram sealed by the session of a player whose address is not
+    /// bound to that player yet.
+    Unbound(String, SocketAddr),
+    /// An authenticated player.
+    Player(String, Transport),
+}
+
+impl Peer {
+    pub fn transport(&self) -> Transport {
+        match self {
+            Peer::Waiting(_) => Transport::Tcp,
+            Peer::Unbound(..) => Transport::Udp,
+            Peer::Player(_, transport) => *transport,
+        }
+    }
+}
+
+/// Whether a handler runs before or after its peer has authenticated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Anonymous,
+    Authenticated,
+}
+
+pub struct Request<'a> {
+    pub peer: Peer,
+    pub context: &'a mut Context,
+}
+
+#[async_trait::async_trait]
+pub trait Handler: Send + Sync {
+    fn access(&self) -> Access {
+        Access::Authenticated
+    }
+
+    async fn handle(
+        &self,
+        incoming: Incoming,
+        request: Request<'_>,
+    ) -> Result<(), Box<dyn Error + Sync + Send>>;
+}
+
+/// Maps every packet type and transport to the handler that owns it.
+///
+/// Packets without a handler are ignored, so a gameplay module only has to
+/// register what it understands.
+#[derive(Default, Clone)]
+pub struct HandlerRegistry {
+    handlers: HashMap<(IncomingKind, Transport), Arc<dyn Handler>>,
+}
+
+impl HandlerRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<H: Handler + 'static>(
+        &mut self,
+        kind: IncomingKind,
+        transport: Transport,
+        handler: H,
+    ) {
+        self.handlers.insert((kind, transport), Arc::new(handler));
+    }
+
+    pub fn get(&self, kind: IncomingKind, transport: Transport) -> Option<Arc<dyn Handler>> {
+        self.handlers.get(&(kind, transport)).cloned()
+    }
+}
+
+pub async fn dispatch(
+    incoming: Incoming,
+    peer: Peer,
+    context: &mut Context,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let kind = incoming.kind();
+
+    let handler = match context.handlers.get(kind, peer.transport()) {
+        Some(handler) => handler,
+        None => return Ok(()),
+    };
+
+    match (handler.access(), &peer) {
+        // A hello retransmitted after its answer got lost is not the client's
+        // fault, so it is dropped rather than treated as an error.
+        (Access::Anonymous, Peer::Player(..)) => Ok(()),
+        (Access::Authenticated, Peer::Waiting(_) | Peer::Unbound(..)) => {
+            Err(format!("{kind:?} needs an authenticated session").into())
+        }
+        _ => handler.handle(incoming, Request { peer, context }).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::testing;
+
+    struct Probe {
+        access: Access,
+        calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl Handler for Probe {
+        fn access(&self) -> Access {
+            self.access
+        }
+
+        async fn handle(
+            &self,
+            _incoming: Incoming,
+            _request: Request<'_>,
+        ) -> Result<(), Box<dyn Error + Sync + Send>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+
+            Ok(())
+        }
+    }
+
+    async fn context_with_probe(access: Access) -> (Context, Arc<AtomicUsize>) {
+        let mut context = testing::context().await;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let mut handlers = HandlerRegistry::new();
+
+        handlers.register(
+            IncomingKind::Pong,
+            Transport::Tcp,
+            Probe {
+                access,
+                calls: calls.clone(),
+            },
+        );
+
+        handlers.register(
+            IncomingKind::Pong,
+            Transport::Udp,
+            Probe {
+                access,
+                calls: calls.clone(),
+            },
+        );
+
+        context.handlers = handlers;
+
+        (context, calls)
+    }
+
+    fn pong() -> Incoming {
+        Incoming::Pong { sequence: 1 }
+    }
+
+    #[tokio::test]
+    async fn anonymous_handler_runs_for_waiting_and_unbound_peers() {
+        let (mut context, calls) = context_with_probe(Access::Anonymous).await;
+
+        dispatch(pong(), Peer::Waiting(0), &mut context)
+            .await
+            .unwrap();
+
+        let addr = "127.0.0.1:1".parse().unwrap();
+
+        dispatch(pong(), Peer::Unbound("alice".into(), addr), &mut context)
+            .await
+            .unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn anonymous_handler_ignores_players() {
+        let (mut context, calls) = context_with_probe(Access::Anonymous).await;
+
+        for transport in [Transport::Tcp, Transport::Udp] {
+            let peer = Peer::Player("alice".into(), transport);
+
+            dispatch(pong(), peer, &mut context).await.unwrap();
+        }
+
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn authenticated_handler_refuses_unauthenticated_peers() {
+        let (mut context, calls) = context_with_probe(Access::Authenticated).await;
+
+        assert!(dispatch(pong(), Peer::Waiting(0), &mut context)
+            .await
+            .is_err());
+
+        let addr = "127.0.0.1:1".parse().unwrap();
+
+        let peer = Peer::Unbound("alice".into(), addr);
+
+        assert!(dispatch(pong(), peer, &mut context).await.is_err());
+
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn authenticated_handler_runs_for_players() {
+        let (mut context, calls) = context_with_probe(Access::Authenticated).await;
+
+        let peer = Peer::Player("alice".into(), Transport::Udp);
+
+        dispatch(pong(), peer, &mut context).await.unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn unregistered_packets_are_ignored() {
+        let (mut context, calls) = context_with_probe(Access::Authenticated).await;
+
+        let packet = Incoming::AckSnapshot { tick: 1 };
+
+        dispatch(packet, Peer::Waiting(0), &mut context)
+            .await
+            .unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,881 @@
+use std::{collections::HashSet, error::Error, net::SocketAddr};
+
+use crate::{
+    auth::{authenticate, PendingAuth},
+    config::DuplicateLoginPolicy,
+    connection::ConnectionId,
+    handler::{Access, Handler, HandlerRegistry, Peer, Request, Transport},
+    heartbeat::Heartbeat,
+    incoming_packet::{Incoming, IncomingKind},
+    job::Job,
+    job_handler::disconnect,
+    outgoing_packet::Outgoing,
+    player::Player,
+    protocol::{
+        negotiate_version, DisconnectReason, CAPABILITIES, CAPABILITY_COMPRESSION,
+        CAPABILITY_RESUME, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
+    reliable::Delivery,
+    schedule::Schedule,
+    session::{resume_session_id, Session},
+    Context,
+};
+
+pub fn register(registry: &mut HandlerRegistry) {
+    registry.register(IncomingKind::TcpHello, Transport::Tcp, TcpHello);
+
+    registry.register(IncomingKind::Resume, Transport::Tcp, Resume);
+
+    registry.register(IncomingKind::UdpHello, Transport::Udp, UdpHello);
+}
+
+/// Checks the token of a waiting connection, see [`accept_tcp`].
+struct TcpHello;
+
+#[async_trait::async_trait]
+impl Handler for TcpHello {
+    fn access(&self) -> Access {
+        Access::Anonymous
+    }
+
+    async fn handle(
+        &self,
+        incoming: Incoming,
+        request: Request<'_>,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let (
+            Incoming::TcpHello {
+                version,
+                capabilities,
+                token,
+            },
+            Peer::Waiting(connection_id),
+        ) = (incoming, request.peer)
+        else {
+            return Err("unexpected packet for tcp hello".into());
+        };
+
+        let context = request.context;
+
+        let version = match negotiate_version(version) {
+            Some(version) => version,
+            None => {
+                if let Some(connection) = context.connections.get_mut(&connection_id) {
+                    let packet = Outgoing::Reject {
+                        reason: format!("protocol version {version} is not supported"),
+                        min_version: MIN_PROTOCOL_VERSION,
+                        max_version: PROTOCOL_VERSION,
+                    };
+
+                    connection.enqueue_farewell(&packet.serilaize()?)?;
+                }
+
+                return Err(format!("unsupported protocol version, {version}").into());
+            }
+        };
+
+        if context.pending_auths.contains_key(&connection_id) {
+            return Err("tcp hello while authenticating".into());
+        }
+
+        context.pending_auths.insert(connection_id, Vec::new());
+
+        let pending = PendingAuth::Tcp {
+            connection_id,
+            version,
+            capabilities,
+        };
+
+        let provider = context.auth_provider.clone();
+
+        authenticate(pending, provider, token, context.job_sender.clone());
+
+        Ok(())
+    }
+}
+
+/// Reclaims a suspended session for a waiting connection, see
+/// [`resume_tcp`].
+struct Resume;
+
+#[async_trait::async_trait]
+impl Handler for Resume {
+    fn access(&self) -> Access {
+        Access::Anonymous
+    }
+
+    async fn handle(
+        &self,
+        incoming: Incoming,
+        request: Request<'_>,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let (Incoming::Resume { token }, Peer::Waiting(connection_id)) = (incoming, request.peer)
+        else {
+            return Err("unexpected packet for resume".into());
+        };
+
+        let context = request.context;
+
+        if context.pending_auths.contains_key(&connection_id) {
+            return Err("resume while authenticating".into());
+        }
+
+        let id = resume_session_id(&token)
+            .and_then(|session_id| context.sessions.get(&session_id))
+            .filter(|session| context.suspended.contains_key(&session.id))
+            .filter(|session| session.verify_resume_token(&token))
+            .map(|session| session.id.clone());
+
+        match id {
+            Some(id) => resume_tcp(connection_id, id, context),
+            None => {
+                if let Some(connection) = context.connections.get_mut(&connection_id) {
+                    let reason = DisconnectReason::ResumeRejected;
+
+                    let packet = Outgoing::Disconnect {
+                        reason,
+                        message: reason.to_string(),
+                    };
+
+                    connection.enqueue_farewell(&packet.serilaize()?)?;
+                }
+
+                Err("unknown resume token".into())
+            }
+        }
+    }
+}
+
+/// Checks the token sent over an unbound address, see [`bind_udp`].
+struct UdpHello;
+
+#[async_trait::async_trait]
+impl Handler for UdpHello {
+    fn access(&self) -> Access {
+        Access::Anonymous
+    }
+
+    async fn handle(
+        &self,
+        incoming: Incoming,
+        request: Request<'_>,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let (Incoming::UdpHello { token }, Peer::Unbound(id, addr)) = (incoming, request.peer)
+        else {
+            return Err("unexpected packet for udp hello".into());
+        };
+
+        let context = request.context;
+
+        let session_id = *context
+            .session_ids
+            .get_by_key(&id)
+            .ok_or("udp hello without a session")?;
+
+        let session = context
+            .sessions
+            .get_mut(&session_id)
+            .ok_or("udp hello without a session")?;
+
+        if session.udp_authenticating {
+            return Ok(());
+        }
+
+        session.udp_authenticating = true;
+
+        let pending = PendingAuth::Udp {
+            id,
+            session_id,
+            addr,
+        };
+
+        let provider = context.auth_provider.clone();
+
+        authenticate(pending, provider, token, context.job_sender.clone());
+
+        Ok(())
+    }
+}
+
+/// Promotes a waiting connection to the player `id` once its token checked
+/// out. If `id` is connected already, the `duplicate_login` policy decides
+/// which of the two connections is dropped. Other players are only welcomed
+/// to a player they did not know yet, so a kicked or suspended player
+/// taking over its place is not announced again.
+pub fn accept_tcp(
+    connection_id: ConnectionId,
+    version: u16,
+    capabilities: u32,
+    id: String,
+    context: &mut Context,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    if let Some(old) = context.tcp_ids.get_by_key(&id).copied() {
+        match context.config.duplicate_login {
+            DuplicateLoginPolicy::Kick => {
+                context.tcp_ids.remove_by_key(&id);
+
+                disconnect(old, DisconnectReason::Replaced, context);
+            }
+            DuplicateLoginPolicy::Reject => {
+                if let Some(connection) = context.connections.get_mut(&connection_id) {
+                    let reason = DisconnectReason::DuplicateLogin;
+
+                    let packet = Outgoing::Disconnect {
+                        reason,
+                        message: reason.to_string(),
+                    };
+
+                    connection.enqueue_farewell(&packet.serilaize()?)?;
+                }
+
+                return Err(format!("{id} is already logged in").into());
+            }
+        }
+    }
+
+    let connection = context
+        .connections
+        .get_mut(&connection_id)
+        .ok_or("no waiting connection")?;
+
+    let mut capabilities = capabilities & CAPABILITIES;
+
+    if capabilities & CAPABILITY_COMPRESSION != 0 && !connection.enable_compression() {
+        capabilities &= !CAPABILITY_COMPRESSION;
+    }
+
+    if let Some(handle) = context.suspended.remove(&id) {
+        context.schedule_queue.cancel(handle);
+    }
+
+    if let Some(session_id) = context.session_ids.remove_by_key(&id) {
+        context.sessions.remove(&session_id);
+
+        context.udp_addrs.remove_by_key(&id);
+
+        context.udp_channels.remove(&id);
+    }
+
+    let session_id = context.next_session_id;
+
+    context.next_session_id = session_id.wrapping_add(1);
+
+    let session = Session::new(id.clone(), session_id, version, capabilities);
+
+    context.session_ids.insert(id.clone(), session_id);
+
+    context.sessions.insert(session_id, session);
+
+    let present = context.players.contains_key(&id);
+
+    context
+        .players
+        .entry(id.clone())
+        .and_modify(|player| player.acked_tick = None)
+        .or_insert_with(|| Player::new(id.clone()));
+
+    greet(&id, context);
+
+    if !present {
+        let packet = Outgoing::Welcome { id: id.clone() };
+
+        let ex = HashSet::from_iter([id.clone()]);
+
+        let schedule = Schedule::instant(Job::BroadcastToTcp(packet, ex));
+
+        context.schedule_queue.push(schedule);
+    }
+
+    attach(connection_id, id, context);
+
+    Ok(())
+}
+
+/// Hands the suspended player `id` over to a waiting connection that
+/// presented its resume token. Other players never saw it leave. UDP was
+/// unbound on suspension, so the client sends `UdpHello` again.
+pub fn resume_tcp(
+    connection_id: ConnectionId,
+    id: String,
+    context: &mut Context,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let compression = context
+        .session(&id)
+        .ok_or("no session to resume")?
+        .capabilities
+        & CAPABILITY_COMPRESSION
+        != 0;
+
+    let connection = context
+        .connections
+        .get_mut(&connection_id)
+        .ok_or("no waiting connection")?;
+
+    let compressed = compression && connection.enable_compression();
+
+    if let Some(handle) = context.suspended.remove(&id) {
+        context.schedule_queue.cancel(handle);
+    }
+
+    let session = context.session_mut(&id).ok_or("no session to resume")?;
+
+    if !compressed {
+        session.capabilities &= !CAPABILITY_COMPRESSION;
+    }
+
+    session.rotate_resume_token();
+
+    session.tcp_heartbeat = Heartbeat::new();
+
+    session.udp_heartbeat = Heartbeat::new();
+
+    greet(&id, context);
+
+    attach(connection_id, id, context);
+
+    Ok(())
+}
+
+/// Sends the session of `id` and the players already around to its client.
+fn greet(id: &str, context: &mut Context) {
+    let session = match context.session(id) {
+        Some(session) => session,
+        None => return,
+    };
+
+    let mut packets = vec![Outgoing::HelloFromTcp {
+        id: id.to_owned(),
+        session_id: session.session_id,
+        secret: session.key().to_vec(),
+        version: session.version,
+        capabilities: session.capabilities,
+    }];
+
+    if session.capabilities & CAPABILITY_RESUME != 0 {
+        packets.push(Outgoing::ResumeToken {
+            token: session.resume_token(),
+        });
+    }
+
+    let ids = context
+        .session_ids
+        .iter()
+        .map(|(id, _)| id.clone())
+        .filter(|other| other != id)
+        .collect();
+
+    packets.push(Outgoing::Introduce { ids });
+
+    for packet in packets {
+        let schedule = Schedule::instant(Job::SendToTcp(packet, id.to_owned()));
+
+        context.schedule_queue.push(schedule);
+    }
+}
+
+/// Binds a connection to the player `id` and starts watching it.
+fn attach(connection_id: ConnectionId, id: String, context: &mut Context) {
+    let is_websocket = context
+        .connections
+        .get(&connection_id)
+        .map(|connection| connection.is_websocket())
+        .unwrap_or(false);
+
+    let bound = context.udp_addrs.get_by_key(&id).is_some();
+
+    context.tcp_ids.insert(id, connection_id);
+
+    let interval = context.config.heartbeat_interval();
+
+    let schedule = Schedule::every(interval, move || Job::Heartbeat(connection_id));
+
+    let handle = context.schedule_queue.push(schedule);
+
+    context.heartbeats.insert(connection_id, handle);
+
+    if is_websocket || bound {
+        context.clear_handshake_deadline(connection_id);
+    } else {
+        let job = Job::UdpHelloTimeout(connection_id);
+
+        let timeout = context.config.udp_hello_timeout();
+
+        context.set_handshake_deadline(connection_id, job, timeout);
+    }
+}
+
+/// Binds `addr` to the player `id` once the token sent over it checked out,
+/// unless the session it was sealed with has been replaced meanwhile, the
+/// player is suspended or the address is bound to another player.
+///
+/// `HelloFromUdp` is answered over the new address itself and retransmitted
+/// until the client acknowledges it, so the client also learns that
+/// datagrams reach it.
+pub fn bind_udp(id: String, session_id: u32, addr: SocketAddr, context: &mut Context) {
+    if context.session_ids.get_by_key(&id) != Some(&session_id)
+        || context.suspended.contains_key(&id)
+    {
+        return;
+    }
+
+    if let Some(other) = context.udp_addrs.get_by_val(&addr) {
+        eprintln!("udp addr {addr} of {other} claimed by {id}");
+
+        return;
+    }
+
+    context.udp_addrs.insert(id.clone(), addr);
+
+    if let Some(connection_id) = context.tcp_ids.get_by_key(&id).copied() {
+        context.clear_handshake_deadline(connection_id);
+    }
+
+    let packet = Outgoing::HelloFromUdp { id: id.clone() };
+
+    let job = Job::SendToUdp(packet, id, Delivery::ReliableOrdered);
+
+    let schedule = Schedule::instant(job);
+
+    context.schedule_queue.push(schedule);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::time;
+
+    use super::*;
+    use crate::{
+        auth::AuthProvider, config::ServerConfig, http_response::AuthResponse, math::Vector3,
+        testing,
+    };
+
+    /// Takes a while to accept every token as alice's, counting the checks.
+    struct Slow(Arc<AtomicUsize>);
+
+    #[async_trait::async_trait]
+    impl AuthProvider for Slow {
+        async fn authenticate(
+            &self,
+            _token: &str,
+        ) -> Result<AuthResponse, Box<dyn Error + Sync + Send>> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+
+            time::sleep(Duration::from_millis(300)).await;
+
+            Ok(AuthResponse {
+                id: "alice".into(),
+                timestamp: String::new(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn repeated_hello_from_a_player_is_ignored() {
+        let mut context = testing::context().await;
+
+        let mut client = testing::login(&mut context, "alice-token", 0).await;
+
+        let received = client.received().await;
+
+        assert!(matches!(received[0], Outgoing::HelloFromTcp { .. }));
+
+        client
+            .send(Incoming::TcpHello {
+                version: PROTOCOL_VERSION,
+                capabilities: 0,
+                token: "alice-token".into(),
+            })
+            .await;
+
+        testing::settle(&mut context).await;
+
+        assert!(client.received().await.is_empty());
+
+        assert!(!client.is_closed());
+
+        assert!(context.tcp_ids.get_by_key(&"alice".to_owned()).is_some());
+    }
+
+    #[tokio::test]
+    async fn legacy_hello_gets_a_reject() {
+        let mut context = testing::context().await;
+
+        let mut client = testing::connect(&mut context).await;
+
+        let token = "t".repeat(crate::incoming_packet::LEGACY_TOKEN_LEN);
+
+        client.send_raw(&[&[1, 0], token.as_bytes()].concat()).await;
+
+        testing::settle(&mut context).await;
+
+        let received = client.received().await;
+
+        assert!(
+            matches!(
+                received[..],
+                [Outgoing::Reject { min_version, max_version, .. }]
+                    if min_version == MIN_PROTOCOL_VERSION && max_version == PROTOCOL_VERSION
+            ),
+            "{received:?}"
+        );
+
+        assert!(client.is_closed());
+    }
+
+    fn resume_config() -> ServerConfig {
+        ServerConfig {
+            resume_grace_ms: 1_000,
+            ..ServerConfig::default()
+        }
+    }
+
+    fn resume_token_of(packets: &[Outgoing]) -> Vec<u8> {
+        packets
+            .iter()
+            .find_map(|packet| match packet {
+                Outgoing::ResumeToken { token } => Some(token.clone()),
+                _ => None,
+            })
+            .expect("no resume token")
+    }
+
+    async fn resume(context: &mut Context, token: Vec<u8>) -> Vec<Outgoing> {
+        let mut client = testing::connect(context).await;
+
+        client.send(Incoming::Resume { token }).await;
+
+        testing::settle(context).await;
+
+        client.received().await
+    }
+
+    fn is_rejected(packets: &[Outgoing]) -> bool {
+        matches!(
+            packets,
+            [Outgoing::Disconnect {
+                reason: DisconnectReason::ResumeRejected,
+                ..
+            }]
+        )
+    }
+
+    #[tokio::test]
+    async fn resume_within_grace_keeps_the_player() {
+        let mut context = testing::context_with(resume_config()).await;
+
+        let mut alice = testing::login(&mut context, "alice-token", CAPABILITY_RESUME).await;
+
+        let mut bob = testing::login(&mut context, "bob-token", 0).await;
+
+        let token = resume_token_of(&alice.received().await);
+
+        bob.received().await;
+
+        drop(alice);
+
+        testing::settle(&mut context).await;
+
+        assert!(context.suspended.contains_key("alice"));
+
+        let session_id = context.session("alice").unwrap().session_id;
+
+        let received = resume(&mut context, token.clone()).await;
+
+        assert!(matches!(
+            &received[0],
+            Outgoing::HelloFromTcp { id, session_id: resumed, .. }
+                if id == "alice" && *resumed == session_id
+        ));
+
+        assert_ne!(resume_token_of(&received), token);
+
+        assert!(!context.suspended.contains_key("alice"));
+
+        assert!(bob.received().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn suspended_player_is_unbound_from_udp_until_it_resumes() {
+        let mut context = testing::context_with(resume_config()).await;
+
+        let mut alice = testing::login(&mut context, "alice-token", CAPABILITY_RESUME).await;
+
+        let received = alice.received().await;
+
+        let token = resume_token_of(&received);
+
+        let udp = testing::UdpClient::new(&context, &received[0]).await;
+
+        let hello = || Incoming::UdpHello {
+            token: "alice-token".into(),
+        };
+
+        udp.send(1, hello()).await;
+
+        testing::settle(&mut context).await;
+
+        let id = "alice".to_owned();
+
+        assert_eq!(context.udp_addrs.get_by_key(&id), Some(&udp.local_addr()));
+
+        drop(alice);
+
+        testing::settle(&mut context).await;
+
+        assert!(context.suspended.contains_key("alice"));
+
+        assert_eq!(context.udp_addrs.get_by_key(&id), None);
+
+        udp.received().await;
+
+        let origin = Vector3 {
+            x: 4.0,
+            y: 0.0,
+            z: 4.0,
+        };
+
+        udp.send(2, Incoming::UpdateOrigin { origin }).await;
+
+        udp.send(3, hello()).await;
+
+        testing::settle(&mut context).await;
+
+        assert!(udp.received().await.is_empty());
+
+        assert_eq!(context.udp_addrs.get_by_key(&id), None);
+
+        assert!(context.player("alice").unwrap().updated_at.is_none());
+
+        let mut client = testing::connect(&mut context).await;
+
+        client.send(Incoming::Resume { token }).await;
+
+        testing::settle(&mut context).await;
+
+        assert!(matches!(
+            client.received().await[0],
+            Outgoing::HelloFromTcp { .. }
+        ));
+
+        udp.send(4, hello()).await;
+
+        testing::settle(&mut context).await;
+
+        assert_eq!(context.udp_addrs.get_by_key(&id), Some(&udp.local_addr()));
+    }
+
+    #[tokio::test]
+    async fn resume_token_is_single_use() {
+        let mut context = testing::context_with(resume_config()).await;
+
+        let mut alice = testing::login(&mut context, "alice-token", CAPABILITY_RESUME).await;
+
+        let token = resume_token_of(&alice.received().await);
+
+        drop(alice);
+
+        testing::settle(&mut context).await;
+
+        let rotated = resume_token_of(&resume(&mut context, token.clone()).await);
+
+        testing::settle(&mut context).await;
+
+        // The connection of the first resume was dropped along with its
+        // client, so the session is suspended again.
+        assert!(context.suspended.contains_key("alice"));
+
+        assert!(is_rejected(&resume(&mut context, token).await));
+
+        let received = resume(&mut context, rotated).await;
+
+        assert!(matches!(&received[0], Outgoing::HelloFromTcp { .. }));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn suspended_player_expires_after_the_grace() {
+        let mut context = testing::context_with(resume_config()).await;
+
+        let mut alice = testing::login(&mut context, "alice-token", CAPABILITY_RESUME).await;
+
+        let mut bob = testing::login(&mut context, "bob-token", 0).await;
+
+        let token = resume_token_of(&alice.received().await);
+
+        bob.received().await;
+
+        let grace = context.config.resume_grace();
+
+        drop(alice);
+
+        testing::run_for(&mut context, grace / 2).await;
+
+        assert!(bob.received().await.is_empty());
+
+        testing::run_for(&mut context, grace).await;
+
+        assert!(bob
+            .received()
+            .await
+            .contains(&Outgoing::GoodBye { id: "alice".into() }));
+
+        assert!(context.player("alice").is_none());
+
+        assert!(is_rejected(&resume(&mut context, token).await));
+    }
+
+    fn disconnected_for(packets: &[Outgoing], expected: DisconnectReason) -> bool {
+        matches!(packets, [Outgoing::Disconnect { reason, .. }] if *reason == expected)
+    }
+
+    #[tokio::test]
+    async fn kick_replaces_the_old_connection_quietly() {
+        let mut context = testing::context().await;
+
+        let mut old = testing::login(&mut context, "alice-token", 0).await;
+
+        let mut bob = testing::login(&mut context, "bob-token", 0).await;
+
+        old.received().await;
+
+        bob.received().await;
+
+        let mut new = testing::login(&mut context, "alice-token", 0).await;
+
+        testing::settle(&mut context).await;
+
+        let received = old.received().await;
+
+        assert!(disconnected_for(&received, DisconnectReason::Replaced));
+
+        assert!(old.is_closed());
+
+        assert!(matches!(
+            &new.received().await[0],
+            Outgoing::HelloFromTcp { id, .. } if id == "alice"
+        ));
+
+        assert!(!new.is_closed());
+
+        assert!(bob.received().await.is_empty());
+
+        assert!(context.player("alice").is_some());
+    }
+
+    #[tokio::test]
+    async fn reject_keeps_the_old_connection() {
+        let config = ServerConfig {
+            duplicate_login: DuplicateLoginPolicy::Reject,
+            ..ServerConfig::default()
+        };
+
+        let mut context = testing::context_with(config).await;
+
+        let mut old = testing::login(&mut context, "alice-token", 0).await;
+
+        let mut bob = testing::login(&mut context, "bob-token", 0).await;
+
+        old.received().await;
+
+        bob.received().await;
+
+        let session_id = context.session("alice").unwrap().session_id;
+
+        let mut new = testing::login(&mut context, "alice-token", 0).await;
+
+        let received = new.received().await;
+
+        assert!(disconnected_for(
+            &received,
+            DisconnectReason::DuplicateLogin
+        ));
+
+        assert!(new.is_closed());
+
+        assert!(old.received().await.is_empty());
+
+        assert!(!old.is_closed());
+
+        assert!(bob.received().await.is_empty());
+
+        assert_eq!(context.session("alice").unwrap().session_id, session_id);
+    }
+
+    #[tokio::test]
+    async fn fresh_login_of_a_suspended_player_is_not_welcomed() {
+        let mut context = testing::context_with(resume_config()).await;
+
+        let alice = testing::login(&mut context, "alice-token", CAPABILITY_RESUME).await;
+
+        let mut bob = testing::login(&mut context, "bob-token", 0).await;
+
+        bob.received().await;
+
+        drop(alice);
+
+        testing::settle(&mut context).await;
+
+        let mut alice = testing::login(&mut context, "alice-token", 0).await;
+
+        assert!(matches!(
+            &alice.received().await[0],
+            Outgoing::HelloFromTcp { .. }
+        ));
+
+        assert!(!context.suspended.contains_key("alice"));
+
+        assert!(bob.received().await.is_empty());
+
+        // A newcomer is still welcomed.
+        let _carol = testing::login(&mut context, "carol-token", 0).await;
+
+        assert_eq!(
+            bob.received().await,
+            [Outgoing::Welcome { id: "carol".into() }]
+        );
+    }
+
+    #[tokio::test]
+    async fn udp_hellos_wait_for_the_token_in_flight() {
+        let mut context = testing::context().await;
+
+        let mut client = testing::login(&mut context, "alice-token", 0).await;
+
+        let received = client.received().await;
+
+        let udp = testing::UdpClient::new(&context, &received[0]).await;
+
+        let checks = Arc::new(AtomicUsize::new(0));
+
+        context.auth_provider = Arc::new(Slow(checks.clone()));
+
+        for nonce in 1..=3 {
+            let hello = Incoming::UdpHello {
+                token: "alice-token".into(),
+            };
+
+            udp.send(nonce, hello).await;
+        }
+
+        testing::settle(&mut context).await;
+
+        assert_eq!(checks.load(Ordering::Relaxed), 1);
+
+        testing::run_for(&mut context, Duration::from_millis(300)).await;
+
+        let id = "alice".to_owned();
+
+        assert_eq!(context.udp_addrs.get_by_key(&id), Some(&udp.local_addr()));
+
+        assert!(!context.session(&id).unwrap().udp_authenticating);
+
+        assert_eq!(checks.load(Ordering::Relaxed), 1);
+    }
+}
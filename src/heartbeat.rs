@@ -0,0 +1,172 @@
+use std::time::Duration;
+
+use tokio::time;
+
+/// Keeps one transport of a session alive and measures its latency.
+///
+/// At most one ping is outstanding. Sending the next ping before the pong of
+/// the previous one arrived counts as a miss, and any timely pong resets the
+/// misses. RTT and jitter are smoothed like TCP's SRTT and RTTVAR, with gains
+/// of 1/8 and 1/4.
+#[derive(Debug, Default)]
+pub struct Heartbeat {
+    sequence: u32,
+    sent_at: Option<time::Instant>,
+    missed: u32,
+    rtt: Option<Duration>,
+    jitter: Duration,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts the next ping and returns its sequence.
+    pub fn ping(&mut self) -> u32 {
+        if self.sent_at.is_some() {
+            self.missed += 1;
+        }
+
+        self.sequence = self.sequence.wrapping_add(1);
+
+        self.sent_at = Some(time::Instant::now());
+
+        self.sequence
+    }
+
+    /// Takes the pong of `sequence`, ignoring any but the latest ping's.
+    pub fn pong(&mut self, sequence: u32) {
+        let sent_at = match self.sent_at {
+            Some(sent_at) if sequence == self.sequence => sent_at,
+            _ => return,
+        };
+
+        let sample = sent_at.elapsed();
+
+        match self.rtt {
+            Some(rtt) => {
+                let deviation = sample.abs_diff(rtt);
+
+                self.jitter = (self.jitter * 3 + deviation) / 4;
+
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            }
+            None => {
+                self.jitter = sample / 2;
+
+                self.rtt = Some(sample);
+            }
+        }
+
+        self.sent_at = None;
+
+        self.missed = 0;
+    }
+
+    pub fn missed(&self) -> u32 {
+        self.missed
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn round_trip(heartbeat: &mut Heartbeat, millis: u64) {
+        let sequence = heartbeat.ping();
+
+        time::advance(Duration::from_millis(millis)).await;
+
+        heartbeat.pong(sequence);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn first_sample_seeds_rtt_and_jitter() {
+        let mut heartbeat = Heartbeat::new();
+
+        assert_eq!(heartbeat.rtt(), None);
+
+        round_trip(&mut heartbeat, 100).await;
+
+        assert_eq!(heartbeat.rtt(), Some(Duration::from_millis(100)));
+
+        assert_eq!(heartbeat.jitter(), Duration::from_millis(50));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn later_samples_are_smoothed() {
+        let mut heartbeat = Heartbeat::new();
+
+        round_trip(&mut heartbeat, 100).await;
+
+        round_trip(&mut heartbeat, 180).await;
+
+        // 7/8 of 100ms plus 1/8 of 180ms, and 3/4 of 50ms plus 1/4 of 80ms.
+        assert_eq!(heartbeat.rtt(), Some(Duration::from_millis(110)));
+
+        assert_eq!(heartbeat.jitter(), Duration::from_micros(57_500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_pings_count_as_missed() {
+        let mut heartbeat = Heartbeat::new();
+
+        heartbeat.ping();
+
+        assert_eq!(heartbeat.missed(), 0);
+
+        let mut sequence = 0;
+
+        for missed in 1..=3 {
+            time::advance(Duration::from_secs(1)).await;
+
+            sequence = heartbeat.ping();
+
+            assert_eq!(heartbeat.missed(), missed);
+        }
+
+        time::advance(Duration::from_millis(40)).await;
+
+        heartbeat.pong(sequence);
+
+        assert_eq!(heartbeat.missed(), 0);
+
+        assert_eq!(heartbeat.rtt(), Some(Duration::from_millis(40)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_or_repeated_pongs_are_ignored() {
+        let mut heartbeat = Heartbeat::new();
+
+        let stale = heartbeat.ping();
+
+        time::advance(Duration::from_secs(1)).await;
+
+        let latest = heartbeat.ping();
+
+        heartbeat.pong(stale);
+
+        assert_eq!(heartbeat.rtt(), None);
+
+        assert_eq!(heartbeat.missed(), 1);
+
+        time::advance(Duration::from_millis(20)).await;
+
+        heartbeat.pong(latest);
+
+        time::advance(Duration::from_secs(1)).await;
+
+        heartbeat.pong(latest);
+
+        assert_eq!(heartbeat.rtt(), Some(Duration::from_millis(20)));
+    }
+}
@@ -0,0 +1,50 @@
+use std::error::Error;
+
+use crate::{
+    handler::{Handler, HandlerRegistry, Peer, Request, Transport},
+    incoming_packet::{Incoming, IncomingKind},
+};
+
+pub fn register(registry: &mut HandlerRegistry) {
+    registry.register(IncomingKind::Pong, Transport::Tcp, Pong);
+
+    registry.register(IncomingKind::Pong, Transport::Udp, Pong);
+}
+
+/// Feeds the answer to a `Ping` into the heartbeat of its transport. The
+/// round trip measured over UDP sets the resend timeout of the player's
+/// reliable channel.
+struct Pong;
+
+#[async_trait::async_trait]
+impl Handler for Pong {
+    async fn handle(
+        &self,
+        incoming: Incoming,
+        request: Request<'_>,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let (Incoming::Pong { sequence }, Peer::Player(id, transport)) = (incoming, request.peer)
+        else {
+            return Err("unexpected packet for pong".into());
+        };
+
+        let context = request.context;
+
+        let heartbeat = match context.session_mut(&id) {
+            Some(session) => session.heartbeat_mut(transport),
+            None => return Ok(()),
+        };
+
+        heartbeat.pong(sequence);
+
+        let (rtt, jitter) = (heartbeat.rtt(), heartbeat.jitter());
+
+        if let (Transport::Udp, Some(rtt)) = (transport, rtt) {
+            if let Some(channel) = context.udp_channels.get_mut(&id) {
+                channel.set_rtt(rtt, jitter);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,67 +1,44 @@
 use std::error::Error;
 
-use crate::math::Vector3;
-
-#[derive(Debug)]
-pub enum Incoming {
-    TcpHello { token: String },
-    UdpHello { token: String },
-    UpdateOrigin { origin: Vector3 },
-    UpdateRotation { y: f32 },
+use crate::{math::Vector3, protocol::packets};
+
+packets! {
+    #[kind(IncomingKind)]
+    #[derive(Debug, PartialEq)]
+    pub enum Incoming {
+        #[extensible]
+        TcpHello = 1 {
+            version: u16,
+            capabilities: u32,
+            token: String,
+        },
+        UdpHello = 2 { token: String },
+        UpdateOrigin = 3 { origin: Vector3 },
+        UpdateRotation = 4 { y: f32 },
+        AckSnapshot = 5 { tick: u32 },
+        Pong = 6 { sequence: u32 },
+        Resume = 7 { token: Vec<u8> },
+    }
 }
 
-impl Incoming {
-    pub fn deserialize(buf: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
-        if buf.len() < 2 {
-            return Err(format!("buffer too short to deserialize, {buf:?}").into());
-        }
-
-        let body = &buf[2..];
-
-        match &buf[..2] {
-            [1, 0] => {
-                if body.len() != 76 {
-                    return Err(format!("invalid size of body").into());
-                }
-
-                Ok(Self::TcpHello {
-                    token: String::from_utf8_lossy(body).to_string(),
-                })
-            }
-            [2, 0] => {
-                if body.len() != 76 {
-                    return Err(format!("invalid size of body").into());
-                }
-
-                Ok(Self::UdpHello {
-                    token: String::from_utf8_lossy(body).to_string(),
-                })
-            }
-            [3, 0] => {
-                if body.len() != 12 {
-                    return Err(format!("invalid size of body").into());
-                }
+/// How many bytes of token a `TcpHello` from before the protocol was
+/// versioned carries, with nothing else in its body.
+pub const LEGACY_TOKEN_LEN: usize = 76;
 
-                let x = f32::from_le_bytes([body[0], body[1], body[2], body[3]]);
-
-                let y = f32::from_le_bytes([body[4], body[5], body[6], body[7]]);
-
-                let z = f32::from_le_bytes([body[8], body[9], body[10], body[11]]);
-
-                let origin = Vector3::new(x, y, z);
-
-                Ok(Self::UpdateOrigin { origin })
-            }
-            [4, 0] => {
-                if body.len() != 4 {
-                    return Err(format!("invalid size of body").into());
-                }
-
-                let y = f32::from_le_bytes([body[0], body[1], body[2], body[3]]);
-
-                Ok(Self::UpdateRotation { y })
-            }
-            n => Err(format!("unexpected packet arrived, {n:?}").into()),
-        }
+impl Incoming {
+    /// Decodes a packet that arrived over a TCP connection.
+    ///
+    /// A legacy `TcpHello` does not decode under the current layout. It is
+    /// read as a hello of version 0 instead, so that the client gets a
+    /// `Reject` rather than a closed socket.
+    pub fn deserialize_tcp(buf: &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        Incoming::deserialize(buf).or_else(|e| match buf {
+            [1, 0, token @ ..] if token.len() == LEGACY_TOKEN_LEN => Ok(Incoming::TcpHello {
+                version: 0,
+                capabilities: 0,
+                token: String::from_utf8_lossy(token).into_owned(),
+            }),
+            _ => Err(e),
+        })
     }
 }
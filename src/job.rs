@@ -1,19 +1,51 @@
 use std::{collections::HashSet, error::Error, net::SocketAddr};
 
-use tokio::net::TcpStream;
+use tokio::{net::TcpStream, time};
 
-use crate::outgoing_packet::Outgoing;
+use crate::{
+    auth::PendingAuth, connection::ConnectionId, http_response::AuthResponse,
+    incoming_packet::Incoming, outgoing_packet::Outgoing, reliable::Delivery,
+};
 
 pub enum Job {
     AcceptFromTcp(TcpStream, SocketAddr),
-    DropFromWaiting(usize, Option<Box<dyn Error + Sync + Send>>),
-    DropFromTcp(String, Option<Box<dyn Error + Sync + Send>>),
-    DropFromUdp(SocketAddr, Option<Box<dyn Error + Sync + Send>>),
-    ReadableFromWaiting(usize),
-    ReadableFromTcp(String),
+    AcceptFromWebSocket(TcpStream, SocketAddr),
+    DropFromTcp(ConnectionId, Option<Box<dyn Error + Sync + Send>>),
+    DropFromUdp(String, SocketAddr, Option<Box<dyn Error + Sync + Send>>),
+    IncomingFromTcp(ConnectionId, Result<Incoming, Box<dyn Error + Sync + Send>>),
     ReadableFromUdp,
+    AuthCompleted(
+        PendingAuth,
+        Result<AuthResponse, Box<dyn Error + Sync + Send>>,
+    ),
+    CheckOutboundToTcp(ConnectionId),
+    TcpHelloTimeout(ConnectionId),
+    UdpHelloTimeout(ConnectionId),
+    Heartbeat(ConnectionId),
+    ExpireSession(String),
     SendToTcp(Outgoing, String),
-    SendToUdp(Outgoing, String),
+    SendToUdp(Outgoing, String, Delivery),
+    ResendToUdp(String, u16),
+    FlushToUdp,
     BroadcastToTcp(Outgoing, HashSet<String>),
-    BroadcastToUdp(Outgoing, HashSet<String>),
+    BroadcastToUdp(Outgoing, HashSet<String>, Delivery),
+    Tick(time::Instant),
+}
+
+impl Job {
+    /// Whether the job still runs while the server drains before exiting.
+    ///
+    /// Jobs that only keep the world running are left behind.
+    pub fn survives_shutdown(&self) -> bool {
+        !matches!(
+            self,
+            Job::Tick(_)
+                | Job::ResendToUdp(..)
+                | Job::CheckOutboundToTcp(_)
+                | Job::TcpHelloTimeout(_)
+                | Job::UdpHelloTimeout(_)
+                | Job::Heartbeat(_)
+                | Job::ExpireSession(_)
+        )
+    }
 }
@@ -1,90 +1,153 @@
-use std::{collections::HashSet, error::Error, io};
+use std::{collections::HashSet, error::Error, io, net::SocketAddr};
+
+use tokio::time;
 
 use crate::{
-    incoming_handler_from_tcp::handle_incoming_from_tcp,
-    incoming_handler_from_udp::handle_incoming_from_udp,
-    incoming_handler_from_waitings::handle_incoming_from_waitings,
+    auth::PendingAuth,
+    connection::{Connection, ConnectionId},
+    handler::{dispatch, Peer, Transport},
+    handshake_handler::{accept_tcp, bind_udp},
     incoming_packet::Incoming,
     job::Job,
-    net::{wrap_tcp_packet, Reader},
     outgoing_packet::Outgoing,
+    protocol::{Batch, DisconnectReason, Wire, CAPABILITY_RESUME},
+    reliable::{Delivery, Header, Resend, HEADER_LEN},
     schedule::Schedule,
+    session::session_id_of,
+    snapshot::{self, Snapshot, SNAPSHOT_OVERHEAD},
+    tick::{self, Tick, MAX_CATCH_UP},
     Context,
 };
 
-pub async fn handle(job: Job, context: &mut Context) -> Result<(), Box<dyn Error>> {
+/// How many packets a connection may send while its token is being checked.
+/// A client that does not wait for its hello to be answered is dropped.
+const MAX_DEFERRED: usize = 16;
+
+pub async fn handle(job: Job, context: &mut Context) -> Result<(), Box<dyn Error + Sync + Send>> {
     match job {
         Job::AcceptFromTcp(stream, _) => {
-            context.waitings.push(stream);
+            let id = context.next_connection_id();
+
+            let connection = Connection::tcp(
+                id,
+                stream,
+                context.incoming_sender.clone(),
+                context.config.read_buffer_size,
+            );
+
+            context.connections.insert(id, connection);
+
+            let timeout = context.config.tcp_hello_timeout();
+
+            context.set_handshake_deadline(id, Job::TcpHelloTimeout(id), timeout);
 
             Ok(())
         }
-        Job::ReadableFromWaiting(index) => {
-            if let Some(stream) = context.waitings.get(index) {
-                let mut buf = [0; 4096];
+        Job::AcceptFromWebSocket(stream, _) => {
+            let id = context.next_connection_id();
 
-                let n = match stream.try_read_packet_buf(&mut buf) {
-                    Ok(n) => n,
-                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
-                    Err(e) => {
-                        let job = Job::DropFromWaiting(index, Some(e.into()));
+            let connection = Connection::websocket(id, stream, context.incoming_sender.clone());
 
-                        let schedule = Schedule::instant(job);
+            context.connections.insert(id, connection);
 
-                        context.schedule_queue.push(schedule);
+            let timeout = context.config.tcp_hello_timeout();
 
-                        return Ok(());
-                    }
-                };
+            context.set_handshake_deadline(id, Job::TcpHelloTimeout(id), timeout);
 
-                let incoming = match Incoming::deserialize(&buf[..n]) {
-                    Ok(incoming) => incoming,
-                    Err(e) => {
-                        let job = Job::DropFromWaiting(index, Some(e.into()));
+            Ok(())
+        }
+        Job::IncomingFromTcp(connection_id, incoming) => {
+            if !context.connections.contains_key(&connection_id) {
+                return Ok(());
+            }
 
-                        let schedule = Schedule::instant(job);
+            let player = context.tcp_ids.get_by_val(&connection_id).cloned();
 
-                        context.schedule_queue.push(schedule);
+            let deferred = context.pending_auths.get_mut(&connection_id);
 
-                        return Ok(());
-                    }
-                };
+            let result = match (incoming, player, deferred) {
+                (Ok(incoming), Some(id), _) => {
+                    let peer = Peer::Player(id, Transport::Tcp);
 
-                if let Err(e) = handle_incoming_from_waitings(incoming, index, context).await {
-                    let job = Job::DropFromWaiting(index, Some(e.into()));
+                    dispatch(incoming, peer, context).await
+                }
+                (Ok(_), None, Some(deferred)) if deferred.len() >= MAX_DEFERRED => {
+                    Err(format!("more than {MAX_DEFERRED} packets while authenticating").into())
+                }
+                (Ok(incoming), None, Some(deferred)) => {
+                    deferred.push(incoming);
 
-                    let schedule = Schedule::instant(job);
+                    Ok(())
+                }
+                (Ok(incoming), None, None) => {
+                    let peer = Peer::Waiting(connection_id);
 
-                    context.schedule_queue.push(schedule);
+                    dispatch(incoming, peer, context).await
+                }
+                (Err(e), _, _) => Err(e),
+            };
+
+            if let Err(e) = result {
+                let schedule = Schedule::instant(Job::DropFromTcp(connection_id, Some(e)));
+
+                context.schedule_queue.push(schedule);
+            }
+
+            Ok(())
+        }
+        Job::ReadableFromUdp => {
+            let mut buf = vec![0; context.config.read_buffer_size];
+
+            let (n, addr) = match context.udp_socket.try_recv_from(&mut buf) {
+                Ok(x) => x,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+
+            let (id, mut body, newest) = match open_datagram(&buf[..n], context) {
+                Ok(x) => x,
+                Err(e) => {
+                    eprintln!("udp datagram from {addr} rejected for {e}");
 
                     return Ok(());
                 }
+            };
 
-                Ok(())
-            } else {
-                Err("no tcp stream".into())
+            if newest {
+                probe_udp(&id, addr, context)?;
             }
-        }
-        Job::ReadableFromTcp(id) => {
-            if let Some(stream) = context.tcp_streams.get(&id) {
-                let mut buf = [0; 4096];
 
-                let n = match stream.try_read_packet_buf(&mut buf) {
-                    Ok(n) => n,
-                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
-                    Err(e) => {
-                        let schedule = Schedule::instant(Job::DropFromTcp(id, Some(e.into())));
+            let header = match Header::decode(&mut body) {
+                Ok(header) => header,
+                Err(e) => {
+                    let schedule = Schedule::instant(Job::DropFromUdp(id.clone(), addr, Some(e)));
 
-                        context.schedule_queue.push(schedule);
+                    context.schedule_queue.push(schedule);
 
-                        return Ok(());
-                    }
-                };
+                    return Ok(());
+                }
+            };
 
-                let incoming = match Incoming::deserialize(&buf[..n]) {
+            let channel = context.udp_channels.entry(id.clone()).or_default();
+
+            let payloads = channel.receive(&header, body);
+
+            if header.delivery == Delivery::ReliableOrdered && !body.is_empty() {
+                let message = channel.ack_only()?;
+
+                if context.udp_addrs.get_by_key(&id) == Some(&addr) {
+                    batch_to_udp(id.clone(), &message, context)?;
+                } else {
+                    send_to_addr(&message, addr, context)?;
+                }
+            }
+
+            for payload in payloads {
+                let incoming = match Incoming::deserialize(&payload) {
                     Ok(incoming) => incoming,
                     Err(e) => {
-                        let schedule = Schedule::instant(Job::DropFromTcp(id, Some(e)));
+                        let schedule =
+                            Schedule::instant(Job::DropFromUdp(id.clone(), addr, Some(e)));
 
                         context.schedule_queue.push(schedule);
 
@@ -92,131 +155,1277 @@ pub async fn handle(job: Job, context: &mut Context) -> Result<(), Box<dyn Error
                     }
                 };
 
-                if let Err(e) = handle_incoming_from_tcp(incoming, id.clone(), context).await {
-                    let schedule = Schedule::instant(Job::DropFromTcp(id, Some(e)));
+                if let Incoming::Pong { sequence } = incoming {
+                    if migrate_udp(&id, addr, sequence, context) {
+                        continue;
+                    }
+                }
+
+                let peer = match context.udp_addrs.get_by_key(&id) == Some(&addr) {
+                    true => Peer::Player(id.clone(), Transport::Udp),
+                    false => Peer::Unbound(id.clone(), addr),
+                };
+
+                if let Err(e) = dispatch(incoming, peer, context).await {
+                    let schedule = Schedule::instant(Job::DropFromUdp(id.clone(), addr, Some(e)));
 
                     context.schedule_queue.push(schedule);
-                }
 
-                Ok(())
-            } else {
-                Err("no tcp stream".into())
+                    return Ok(());
+                }
             }
-        }
-        Job::ReadableFromUdp => {
-            let mut buf = [0; 4096];
 
-            let (n, addr) = match context.udp_socket.try_recv_from(&mut buf) {
-                Ok(x) => x,
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
-                Err(e) => return Err(e.into()),
+            Ok(())
+        }
+        Job::AuthCompleted(
+            PendingAuth::Tcp {
+                connection_id,
+                version,
+                capabilities,
+            },
+            result,
+        ) => {
+            let deferred = match context.pending_auths.remove(&connection_id) {
+                Some(deferred) => deferred,
+                None => return Ok(()),
             };
 
-            let incoming = match Incoming::deserialize(&buf[..n]) {
-                Ok(incoming) => incoming,
+            let result = result.and_then(|response| {
+                accept_tcp(
+                    connection_id,
+                    version,
+                    capabilities,
+                    response.id.clone(),
+                    context,
+                )
+                .map(|_| response.id)
+            });
+
+            match result {
+                Ok(id) => {
+                    for incoming in deferred {
+                        let peer = Peer::Player(id.clone(), Transport::Tcp);
+
+                        if let Err(e) = dispatch(incoming, peer, context).await {
+                            let job = Job::DropFromTcp(connection_id, Some(e));
+
+                            let schedule = Schedule::instant(job);
+
+                            context.schedule_queue.push(schedule);
+
+                            break;
+                        }
+                    }
+                }
                 Err(e) => {
-                    let schedule = Schedule::instant(Job::DropFromUdp(addr, Some(e.into())));
+                    let job = Job::DropFromTcp(connection_id, Some(e));
+
+                    let schedule = Schedule::instant(job);
 
                     context.schedule_queue.push(schedule);
+                }
+            }
 
-                    return Ok(());
+            Ok(())
+        }
+        Job::AuthCompleted(
+            PendingAuth::Udp {
+                id,
+                session_id,
+                addr,
+            },
+            result,
+        ) => {
+            if let Some(session) = context.sessions.get_mut(&session_id) {
+                session.udp_authenticating = false;
+            }
+
+            let result = result.and_then(|response| match response.id == id {
+                true => Ok(()),
+                false => Err("token does not match session".into()),
+            });
+
+            match result {
+                Ok(_) => bind_udp(id, session_id, addr, context),
+                Err(e) => {
+                    let schedule = Schedule::instant(Job::DropFromUdp(id.clone(), addr, Some(e)));
+
+                    context.schedule_queue.push(schedule);
                 }
-            };
+            }
 
-            if let Err(e) = handle_incoming_from_udp(incoming, addr, context).await {
-                let schedule = Schedule::instant(Job::DropFromUdp(addr, Some(e)));
+            Ok(())
+        }
+        Job::CheckOutboundToTcp(connection_id) => {
+            check_outbound_to_tcp(connection_id, context);
 
-                context.schedule_queue.push(schedule);
+            Ok(())
+        }
+        Job::TcpHelloTimeout(connection_id) => {
+            context.handshake_deadlines.remove(&connection_id);
+
+            if context.tcp_ids.get_by_val(&connection_id).is_none() {
+                disconnect(connection_id, DisconnectReason::HandshakeTimeout, context);
             }
 
             Ok(())
         }
-        Job::DropFromWaiting(index, e) => {
-            if let Some(e) = e {
-                eprintln!("waiting dropped for {e:?}");
+        Job::UdpHelloTimeout(connection_id) => {
+            context.handshake_deadlines.remove(&connection_id);
+
+            let unbound = match context.tcp_ids.get_by_val(&connection_id) {
+                Some(id) => context.udp_addrs.get_by_key(id).is_none(),
+                None => false,
+            };
+
+            if unbound {
+                disconnect(connection_id, DisconnectReason::UdpHelloTimeout, context);
             }
 
-            context.waitings.remove(index);
+            Ok(())
+        }
+        Job::Heartbeat(connection_id) => {
+            let id = match context.tcp_ids.get_by_val(&connection_id) {
+                Some(id) => id.clone(),
+                None => return Ok(()),
+            };
+
+            let bound = context.udp_addrs.get_by_key(&id).is_some();
+
+            let max_missed = context.config.heartbeat_max_missed;
+
+            let session = match context.session_mut(&id) {
+                Some(session) => session,
+                None => return Ok(()),
+            };
+
+            if session.tcp_heartbeat.missed() >= max_missed
+                || session.udp_heartbeat.missed() >= max_missed
+            {
+                disconnect(connection_id, DisconnectReason::HeartbeatTimeout, context);
+
+                return Ok(());
+            }
+
+            let tcp_sequence = session.tcp_heartbeat.ping();
+
+            let udp_sequence = bound.then(|| session.udp_heartbeat.ping());
+
+            let packet = Outgoing::Ping {
+                sequence: tcp_sequence,
+            };
+
+            send_to_tcp(id.clone(), packet.serilaize()?, context)?;
+
+            if let Some(sequence) = udp_sequence {
+                let packet = Outgoing::Ping { sequence };
+
+                send_to_udp(id, packet.serilaize()?, Delivery::Unreliable, context)?;
+            }
 
             Ok(())
         }
-        Job::DropFromTcp(id, e) => {
+        Job::DropFromTcp(connection_id, e) => {
             if let Some(e) = e {
                 eprintln!("tcp stream dropped for {e:?}");
             }
 
-            context.tcp_streams.remove(&id);
+            if let Some(connection) = context.connections.remove(&connection_id) {
+                connection.release();
+            }
 
-            context.udp_addrs.remove_by_key(&id);
+            context.pending_auths.remove(&connection_id);
 
-            let packet = Outgoing::GoodBye { id };
+            context.clear_handshake_deadline(connection_id);
 
-            let schedule = Schedule::instant(Job::BroadcastToTcp(packet, HashSet::new()));
+            if let Some(handle) = context.heartbeats.remove(&connection_id) {
+                context.schedule_queue.cancel(handle);
+            }
 
-            context.schedule_queue.push(schedule);
+            let id = match context.tcp_ids.remove_by_value(&connection_id) {
+                Some(id) => id,
+                None => return Ok(()),
+            };
+
+            let resumable = context
+                .session(&id)
+                .map(|session| session.capabilities & CAPABILITY_RESUME != 0)
+                .unwrap_or(false);
+
+            let grace = context.config.resume_grace();
+
+            if resumable && !grace.is_zero() {
+                let deadline = time::Instant::now() + grace;
+
+                let schedule = Schedule::new(Job::ExpireSession(id.clone()), deadline);
+
+                let handle = context.schedule_queue.push(schedule);
+
+                // The client binds UDP again once it resumes, so nothing is
+                // sent to or taken from the old address meanwhile.
+                unbind_udp(&id, context);
+
+                context.suspended.insert(id, handle);
+
+                return Ok(());
+            }
+
+            remove_player(id, context);
+
+            Ok(())
+        }
+        Job::ExpireSession(id) => {
+            if context.suspended.remove(&id).is_some() {
+                remove_player(id, context);
+            }
 
             Ok(())
         }
-        Job::DropFromUdp(addr, e) => {
+        Job::DropFromUdp(id, addr, e) => {
             if let Some(e) = e {
-                eprintln!("udp addr dropped for {e:?}");
+                eprintln!("udp addr {addr} of {id} dropped for {e:?}");
             }
 
-            context.udp_addrs.remove_by_value(&addr);
+            if context.udp_addrs.get_by_key(&id) == Some(&addr) {
+                context.udp_addrs.remove_by_key(&id);
+
+                context.udp_channels.remove(&id);
+            }
 
             Ok(())
         }
         Job::SendToTcp(packet, id) => {
-            if let Some(stream) = context.tcp_streams.get(&id) {
-                let buf = packet.serilaize()?;
-
-                let buf = wrap_tcp_packet(&buf);
+            let buf = packet.serilaize()?;
 
-                stream.try_write(&buf)?;
+            send_to_tcp(id, buf, context)
+        }
+        Job::SendToUdp(packet, id, delivery) => {
+            let buf = packet.serilaize()?;
 
-                Ok(())
-            } else {
-                Err("no stream to send".into())
-            }
+            send_to_udp(id, buf, delivery, context)
         }
-        Job::SendToUdp(packet, id) => {
-            if let Some(addr) = context.udp_addrs.get_by_key(&id) {
-                let buf = packet.serilaize()?;
+        Job::ResendToUdp(id, sequence) => {
+            if let (Some(addr), Some(channel)) = (
+                context.udp_addrs.get_by_key(&id),
+                context.udp_channels.get_mut(&id),
+            ) {
+                match channel.resend(sequence) {
+                    Ok(Some(Resend { message, timeout })) => {
+                        let deadline = time::Instant::now() + timeout;
+
+                        let schedule =
+                            Schedule::new(Job::ResendToUdp(id.clone(), sequence), deadline);
+
+                        context.schedule_queue.push(schedule);
+
+                        batch_to_udp(id, &message, context)?;
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        let schedule =
+                            Schedule::instant(Job::DropFromUdp(id.clone(), *addr, Some(e)));
 
-                context.udp_socket.try_send_to(&buf, addr.clone())?;
+                        context.schedule_queue.push(schedule);
+                    }
+                }
+            }
 
-                Ok(())
-            } else {
-                Err("no stream to send".into())
+            Ok(())
+        }
+        Job::FlushToUdp => {
+            for (id, mut batch) in context.udp_batches.drain() {
+                if let Some(addr) = context.udp_addrs.get_by_key(&id) {
+                    for datagram in batch.take() {
+                        if let Err(e) = context.udp_socket.try_send_to(&datagram, *addr) {
+                            eprintln!("udp flush to {id} failed for {e}");
+                        }
+                    }
+                }
             }
+
+            Ok(())
         }
         Job::BroadcastToTcp(packet, ex) => {
             let buf = packet.serilaize()?;
 
-            let buf = wrap_tcp_packet(&buf);
+            let ids = context
+                .tcp_ids
+                .iter()
+                .map(|(id, _)| id)
+                .filter(|id| !ex.contains(*id))
+                .cloned()
+                .collect::<Vec<_>>();
 
-            for (id, stream) in context.tcp_streams.iter() {
-                if ex.contains(id) {
-                    continue;
+            for id in ids {
+                if let Err(e) = send_to_tcp(id, buf.clone(), context) {
+                    eprintln!("tcp broadcast failed for {e}");
                 }
-
-                stream.try_write(&buf)?;
             }
 
             Ok(())
         }
-        Job::BroadcastToUdp(packet, ex) => {
+        Job::BroadcastToUdp(packet, ex, delivery) => {
             let buf = packet.serilaize()?;
 
-            for (id, addr) in context.udp_addrs.iter() {
-                if ex.contains(id) {
-                    continue;
+            let ids = context
+                .udp_addrs
+                .iter()
+                .map(|(id, _)| id)
+                .filter(|id| !ex.contains(*id))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            for id in ids {
+                if let Err(e) = send_to_udp(id, buf.clone(), delivery, context) {
+                    eprintln!("udp broadcast failed for {e}");
                 }
+            }
+
+            Ok(())
+        }
+        Job::Tick(deadline) => {
+            let now = time::Instant::now();
 
-                context.udp_socket.try_send_to(&buf, addr.clone())?;
+            let delta = tick::interval(context.config.tick_rate);
+
+            let mut next = deadline + delta;
+
+            let mut ticks = 1;
+
+            while next <= now && ticks < MAX_CATCH_UP {
+                next += delta;
+
+                ticks += 1;
+            }
+
+            if next <= now {
+                eprintln!("tick fell behind by {:?}", now - next);
+
+                next = now + delta;
+            }
+
+            let schedule = Schedule::new(Job::Tick(next), next);
+
+            context.schedule_queue.push(schedule);
+
+            let hooks = context.tick_hooks.clone();
+
+            let mut tick = Tick { number: 0, delta };
+
+            for _ in 0..ticks {
+                context.tick += 1;
+
+                tick.number = context.tick;
+
+                for hook in hooks.timers.iter().chain(hooks.simulation.iter()) {
+                    hook(context, &tick);
+                }
+            }
+
+            for hook in hooks.snapshots.iter() {
+                hook(context, &tick);
             }
 
             Ok(())
         }
     }
 }
+
+pub fn send_snapshots(context: &mut Context, tick: &Tick) {
+    let snapshot = Snapshot {
+        tick: tick.number,
+        transforms: context
+            .players
+            .values()
+            .filter(|player| player.updated_at.is_some())
+            .map(|player| (player.id.clone(), player.transform()))
+            .collect(),
+    };
+
+    let ids = context
+        .tcp_ids
+        .iter()
+        .filter(|(id, connection_id)| {
+            let is_websocket = context
+                .connections
+                .get(connection_id)
+                .map(|connection| connection.is_websocket())
+                .unwrap_or(false);
+
+            is_websocket || context.udp_addrs.get_by_key(id).is_some()
+        })
+        .map(|(id, _)| id.clone())
+        .collect::<Vec<_>>();
+
+    let budget = context.config.udp_mtu - 2 - HEADER_LEN - SNAPSHOT_OVERHEAD;
+
+    for id in ids {
+        let base = context
+            .player(&id)
+            .and_then(|player| player.acked_tick)
+            .and_then(|tick| context.snapshots.get(tick));
+
+        let (entities, removed) = snapshot.delta(base);
+
+        let base = base.map(|base| base.tick).unwrap_or(0);
+
+        let parts = match snapshot::split(entities, removed, budget) {
+            Ok(parts) => parts,
+            Err(e) => {
+                eprintln!("snapshot failed for {e}");
+
+                continue;
+            }
+        };
+
+        let count = parts.len();
+
+        // Parts of one tick must not drop each other as out of sequence.
+        let delivery = match count {
+            1 => Delivery::UnreliableSequenced,
+            _ => Delivery::Unreliable,
+        };
+
+        for (part, (entities, removed)) in parts.into_iter().enumerate() {
+            let packet = Outgoing::Snapshot {
+                tick: snapshot.tick,
+                base,
+                part: part as u16,
+                parts: count as u16,
+                entities,
+                removed,
+            };
+
+            let result = packet.serilaize().and_then(|buf| {
+                if context.udp_addrs.get_by_key(&id).is_some() {
+                    send_to_udp(id.clone(), buf, delivery, context)
+                } else {
+                    send_to_tcp(id.clone(), buf, context)
+                }
+            });
+
+            if let Err(e) = result {
+                eprintln!("snapshot failed for {e}");
+
+                break;
+            }
+        }
+    }
+
+    context.snapshots.push(snapshot);
+}
+
+/// Follows a bound player to `addr` when a NAT rebinds its port.
+///
+/// Only the newest datagram of a session may start a move, so a late
+/// datagram from the old address cannot move it back. The new address has
+/// to echo a `Ping` before it is bound, see [`migrate_udp`], and an address
+/// bound to another player is never taken over. Players that never sent a
+/// `UdpHello` still have to.
+fn probe_udp(
+    id: &str,
+    addr: SocketAddr,
+    context: &mut Context,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    match context.udp_addrs.get_by_key(&id.to_owned()) {
+        Some(old) if *old != addr => {}
+        _ => return Ok(()),
+    }
+
+    if let Some(other) = context.udp_addrs.get_by_val(&addr) {
+        eprintln!("udp addr {addr} of {other} claimed by {id}");
+
+        return Ok(());
+    }
+
+    let session = context.session_mut(id).ok_or("no session to probe")?;
+
+    // Every newest datagram from the address repeats the same challenge, in
+    // case a ping got lost.
+    let sequence = match session.path_probe {
+        Some((probed, sequence)) if probed == addr => sequence,
+        _ => rand::random(),
+    };
+
+    session.path_probe = Some((addr, sequence));
+
+    let packet = Outgoing::Ping { sequence };
+
+    let channel = context.udp_channels.entry(id.to_owned()).or_default();
+
+    let (message, _) = channel.send(Delivery::Unreliable, packet.serilaize()?)?;
+
+    send_to_addr(&message, addr, context)
+}
+
+/// Moves the UDP address of `id` to `addr` if `sequence` answers the probe
+/// of that address, returning whether it did.
+fn migrate_udp(id: &str, addr: SocketAddr, sequence: u32, context: &mut Context) -> bool {
+    let session = match context.session_mut(id) {
+        Some(session) if session.path_probe == Some((addr, sequence)) => session,
+        _ => return false,
+    };
+
+    session.path_probe = None;
+
+    if context.udp_addrs.get_by_val(&addr).is_some() {
+        return false;
+    }
+
+    match context.udp_addrs.get_by_key(&id.to_owned()) {
+        Some(old) => eprintln!("udp addr of {id} moved from {old} to {addr}"),
+        None => return false,
+    }
+
+    context.udp_addrs.insert(id.to_owned(), addr);
+
+    true
+}
+
+fn open_datagram<'a>(
+    datagram: &'a [u8],
+    context: &mut Context,
+) -> Result<(String, &'a [u8], bool), Box<dyn Error + Sync + Send>> {
+    let session_id = session_id_of(datagram)?;
+
+    if let Some(session) = context.sessions.get_mut(&session_id) {
+        let (body, newest) = session.open(datagram)?;
+
+        Ok((session.id.clone(), body, newest))
+    } else {
+        Err(format!("no session {session_id}").into())
+    }
+}
+
+fn send_to_tcp(
+    id: String,
+    buf: Vec<u8>,
+    context: &mut Context,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let connection_id = context.tcp_ids.get_by_key(&id).copied();
+
+    if let Some(connection_id) = connection_id {
+        context
+            .connections
+            .get_mut(&connection_id)
+            .ok_or("no stream to send")?
+            .enqueue_packet(&buf)?;
+
+        check_outbound_to_tcp(connection_id, context);
+
+        Ok(())
+    } else {
+        Err("no stream to send".into())
+    }
+}
+
+fn send_to_udp(
+    id: String,
+    buf: Vec<u8>,
+    delivery: Delivery,
+    context: &mut Context,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    if context.udp_addrs.get_by_key(&id).is_some() {
+        let channel = context.udp_channels.entry(id.clone()).or_default();
+
+        let (message, sequence) = channel.send(delivery, buf)?;
+
+        if let Some(sequence) = sequence {
+            let deadline = time::Instant::now() + channel.resend_timeout();
+
+            let schedule = Schedule::new(Job::ResendToUdp(id.clone(), sequence), deadline);
+
+            context.schedule_queue.push(schedule);
+        }
+
+        batch_to_udp(id, &message, context)
+    } else {
+        Err("no stream to send".into())
+    }
+}
+
+/// Sends one message to `addr` right away, outside of any player's batch.
+fn send_to_addr(
+    message: &[u8],
+    addr: SocketAddr,
+    context: &mut Context,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let mut batch = Batch::new(context.config.udp_mtu);
+
+    batch.push(message)?;
+
+    for datagram in batch.take() {
+        context.udp_socket.try_send_to(&datagram, addr)?;
+    }
+
+    Ok(())
+}
+
+fn batch_to_udp(
+    id: String,
+    message: &[u8],
+    context: &mut Context,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let mtu = context.config.udp_mtu;
+
+    context
+        .udp_batches
+        .entry(id)
+        .or_insert_with(|| Batch::new(mtu))
+        .push(message)
+}
+
+fn check_outbound_to_tcp(connection_id: ConnectionId, context: &mut Context) {
+    let limits = context.config.outbound_limits();
+
+    if let Some(connection) = context.connections.get_mut(&connection_id) {
+        let was_congested = connection.is_congested();
+
+        match connection.check_backpressure(&limits) {
+            Ok(_) if !was_congested && connection.is_congested() => {
+                let deadline = time::Instant::now() + limits.grace;
+
+                let schedule = Schedule::new(Job::CheckOutboundToTcp(connection_id), deadline);
+
+                context.schedule_queue.push(schedule);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                let schedule = Schedule::instant(Job::DropFromTcp(connection_id, Some(e)));
+
+                context.schedule_queue.push(schedule);
+            }
+        }
+    }
+}
+
+/// Forgets everything about the player `id` and tells the others it left.
+/// Forgets the UDP address of `id` along with what was sent over it.
+fn unbind_udp(id: &str, context: &mut Context) {
+    let id = id.to_owned();
+
+    context.udp_addrs.remove_by_key(&id);
+
+    context.udp_channels.remove(&id);
+
+    context.udp_batches.remove(&id);
+
+    if let Some(session) = context.session_mut(&id) {
+        session.path_probe = None;
+    }
+}
+
+fn remove_player(id: String, context: &mut Context) {
+    unbind_udp(&id, context);
+
+    if let Some(session_id) = context.session_ids.remove_by_key(&id) {
+        context.sessions.remove(&session_id);
+    }
+
+    context.players.remove(&id);
+
+    let packet = Outgoing::GoodBye { id };
+
+    let schedule = Schedule::instant(Job::BroadcastToTcp(packet, HashSet::new()));
+
+    context.schedule_queue.push(schedule);
+}
+
+/// Tells a connection why it is being dropped, then drops it. The notice is
+/// still written out after the connection is gone.
+pub fn disconnect(connection_id: ConnectionId, reason: DisconnectReason, context: &mut Context) {
+    if let Some(connection) = context.connections.get_mut(&connection_id) {
+        let packet = Outgoing::Disconnect {
+            reason,
+            message: reason.to_string(),
+        };
+
+        let result = packet
+            .serilaize()
+            .and_then(|buf| connection.enqueue_farewell(&buf));
+
+        if let Err(e) = result {
+            eprintln!("disconnect notice failed for {e}");
+        }
+    }
+
+    let schedule = Schedule::instant(Job::DropFromTcp(
+        connection_id,
+        Some(reason.to_string().into()),
+    ));
+
+    context.schedule_queue.push(schedule);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future, sync::Arc, time::Duration};
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::{TcpStream, UdpSocket};
+    use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+
+    use super::*;
+    use crate::math::Vector3;
+    use crate::{
+        auth::AuthProvider, config::ServerConfig, http_response::AuthResponse,
+        protocol::PROTOCOL_VERSION, testing,
+    };
+
+    /// Never answers, so connections stay authenticating.
+    struct Stalled;
+
+    #[async_trait::async_trait]
+    impl AuthProvider for Stalled {
+        async fn authenticate(
+            &self,
+            _token: &str,
+        ) -> Result<AuthResponse, Box<dyn Error + Sync + Send>> {
+            future::pending().await
+        }
+    }
+
+    async fn authenticating(packets: usize) -> testing::Client {
+        let mut context = testing::context().await;
+
+        context.auth_provider = Arc::new(Stalled);
+
+        let mut client = testing::connect(&mut context).await;
+
+        client
+            .send(Incoming::TcpHello {
+                version: PROTOCOL_VERSION,
+                capabilities: 0,
+                token: "alice-token".into(),
+            })
+            .await;
+
+        for sequence in 0..packets as u32 {
+            client.send(Incoming::Pong { sequence }).await;
+        }
+
+        testing::settle(&mut context).await;
+
+        client.received().await;
+
+        client
+    }
+
+    #[tokio::test]
+    async fn packets_are_deferred_while_authenticating() {
+        let client = authenticating(MAX_DEFERRED).await;
+
+        assert!(!client.is_closed());
+    }
+
+    #[tokio::test]
+    async fn too_many_deferred_packets_drop_the_connection() {
+        let client = authenticating(MAX_DEFERRED + 1).await;
+
+        assert!(client.is_closed());
+    }
+
+    #[tokio::test]
+    async fn known_token_is_accepted() {
+        let mut context = testing::context().await;
+
+        let mut client = testing::login(&mut context, "alice-token", 0).await;
+
+        let received = client.received().await;
+
+        assert!(matches!(
+            &received[0],
+            Outgoing::HelloFromTcp { id, .. } if id == "alice"
+        ));
+
+        assert!(context.player("alice").is_some());
+    }
+
+    #[tokio::test]
+    async fn unknown_token_drops_the_connection() {
+        let mut context = testing::context().await;
+
+        let mut client = testing::login(&mut context, "mallory-token", 0).await;
+
+        assert!(client.received().await.is_empty());
+
+        assert!(client.is_closed());
+
+        assert!(context.players.is_empty());
+    }
+
+    /// A packet of about `len` bytes.
+    fn bulk(len: usize) -> Outgoing {
+        Outgoing::Introduce {
+            ids: vec!["x".repeat(98); len / 100],
+        }
+    }
+
+    fn outbound_config(hard_limit: usize, grace_ms: u64) -> ServerConfig {
+        ServerConfig {
+            outbound_high_water: 16 * 1024,
+            outbound_hard_limit: hard_limit,
+            outbound_grace_ms: grace_ms,
+            ..ServerConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn outbound_queue_past_the_hard_limit_drops_at_once() {
+        let mut context = testing::context_with(outbound_config(32 * 1024, 60_000)).await;
+
+        let mut client = testing::login(&mut context, "alice-token", 0).await;
+
+        handle(Job::SendToTcp(bulk(60_000), "alice".into()), &mut context)
+            .await
+            .unwrap();
+
+        testing::settle(&mut context).await;
+
+        assert!(context.connections.is_empty());
+
+        assert!(context.player("alice").is_none());
+
+        client.received().await;
+
+        assert!(client.is_closed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn congested_client_is_dropped_after_the_grace_period() {
+        let mut context = testing::context_with(outbound_config(usize::MAX, 300)).await;
+
+        let mut client = testing::login(&mut context, "alice-token", 0).await;
+
+        let packets = 512;
+
+        for _ in 0..packets {
+            handle(Job::SendToTcp(bulk(60_000), "alice".into()), &mut context)
+                .await
+                .unwrap();
+        }
+
+        testing::run_for(&mut context, Duration::from_millis(150)).await;
+
+        let connection = context.connections.values().next().unwrap();
+
+        assert!(connection.is_congested());
+
+        testing::run_for(&mut context, Duration::from_millis(300)).await;
+
+        assert!(context.connections.is_empty());
+
+        let received = client.received().await;
+
+        assert!(client.is_closed());
+
+        assert!(received.len() < packets);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_client_times_out_after_missed_heartbeats() {
+        let mut context = testing::context().await;
+
+        let mut client = testing::login(&mut context, "alice-token", 0).await;
+
+        client.received().await;
+
+        let interval = context.config.heartbeat_interval();
+
+        // The first ping is not a miss, so the last allowed one goes out
+        // one interval after max_missed intervals.
+        let patience = interval * context.config.heartbeat_max_missed;
+
+        testing::run_for(&mut context, patience).await;
+
+        assert!(client.received().await.is_empty());
+
+        testing::run_for(&mut context, interval * 2).await;
+
+        let received = client.received().await;
+
+        assert!(matches!(
+            received[..],
+            [Outgoing::Disconnect {
+                reason: DisconnectReason::HeartbeatTimeout,
+                ..
+            }]
+        ));
+    }
+
+    async fn bind(context: &mut Context, token: &str) -> (testing::Client, testing::UdpClient) {
+        let mut client = testing::login(context, token, 0).await;
+
+        let received = client.received().await;
+
+        let udp = testing::UdpClient::new(context, &received[0]).await;
+
+        udp.send(
+            1,
+            Incoming::UdpHello {
+                token: token.into(),
+            },
+        )
+        .await;
+
+        testing::settle(context).await;
+
+        let id = context.session_ids.get_by_val(&udp.session_id()).unwrap();
+
+        assert_eq!(context.udp_addrs.get_by_key(id), Some(&udp.local_addr()));
+
+        (client, udp)
+    }
+
+    fn addr_of(context: &Context, id: &str) -> Option<SocketAddr> {
+        context.udp_addrs.get_by_key(&id.to_owned()).copied()
+    }
+
+    fn probe_of(packets: &[Outgoing]) -> Option<u32> {
+        packets.iter().find_map(|packet| match packet {
+            Outgoing::Ping { sequence } => Some(*sequence),
+            _ => None,
+        })
+    }
+
+    #[tokio::test]
+    async fn udp_moves_once_the_new_address_answers_its_probe() {
+        let mut context = testing::context().await;
+
+        let (_client, mut udp) = bind(&mut context, "alice-token").await;
+
+        let old_addr = udp.local_addr();
+
+        let moved = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let old = std::mem::replace(&mut udp.socket, moved);
+
+        udp.send(10, Incoming::AckSnapshot { tick: 0 }).await;
+
+        testing::settle(&mut context).await;
+
+        assert_eq!(addr_of(&context, "alice"), Some(old_addr));
+
+        let sequence = probe_of(&udp.received().await).expect("no probe");
+
+        udp.send(
+            11,
+            Incoming::Pong {
+                sequence: sequence.wrapping_add(1),
+            },
+        )
+        .await;
+
+        testing::settle(&mut context).await;
+
+        assert_eq!(addr_of(&context, "alice"), Some(old_addr));
+
+        udp.send(12, Incoming::Pong { sequence }).await;
+
+        testing::settle(&mut context).await;
+
+        assert_eq!(addr_of(&context, "alice"), Some(udp.local_addr()));
+
+        // A late datagram from the old address does not move it back.
+        let moved = std::mem::replace(&mut udp.socket, old);
+
+        udp.send(5, Incoming::AckSnapshot { tick: 0 }).await;
+
+        testing::settle(&mut context).await;
+
+        assert_eq!(probe_of(&udp.received().await), None);
+
+        assert_eq!(
+            addr_of(&context, "alice"),
+            Some(moved.local_addr().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn udp_never_takes_the_address_of_another_player() {
+        let mut context = testing::context().await;
+
+        let (_alice, mut alice_udp) = bind(&mut context, "alice-token").await;
+
+        let (_bob, mut bob_udp) = bind(&mut context, "bob-token").await;
+
+        let (alice_addr, bob_addr) = (alice_udp.local_addr(), bob_udp.local_addr());
+
+        std::mem::swap(&mut alice_udp.socket, &mut bob_udp.socket);
+
+        alice_udp.send(10, Incoming::AckSnapshot { tick: 0 }).await;
+
+        testing::settle(&mut context).await;
+
+        assert!(context.session("alice").unwrap().path_probe.is_none());
+
+        assert_eq!(addr_of(&context, "alice"), Some(alice_addr));
+
+        assert_eq!(addr_of(&context, "bob"), Some(bob_addr));
+    }
+
+    #[tokio::test]
+    async fn snapshots_only_carry_players_that_moved() {
+        let mut context = testing::context().await;
+
+        let (_alice, alice_udp) = bind(&mut context, "alice-token").await;
+
+        let _bob = testing::login(&mut context, "bob-token", 0).await;
+
+        let origin = Vector3 {
+            x: 1.0,
+            y: 0.0,
+            z: 2.0,
+        };
+
+        alice_udp.send(2, Incoming::UpdateOrigin { origin }).await;
+
+        testing::settle(&mut context).await;
+
+        alice_udp.received().await;
+
+        testing::settle(&mut context).await;
+
+        let entities = alice_udp
+            .received()
+            .await
+            .into_iter()
+            .filter_map(|packet| match packet {
+                Outgoing::Snapshot {
+                    base: 0, entities, ..
+                } => Some(entities),
+                _ => None,
+            })
+            .next_back()
+            .expect("no snapshot");
+
+        assert_eq!(
+            entities,
+            [snapshot::EntityDelta {
+                id: "alice".into(),
+                origin: Some(origin),
+                rotation: Some(0.0),
+            }]
+        );
+    }
+
+    /// Accepts a WebSocket client and logs it in with `token`.
+    async fn login_over_websocket(
+        context: &mut Context,
+        token: &str,
+    ) -> WebSocketStream<TcpStream> {
+        let addr = context.ws_listener.as_ref().unwrap().local_addr().unwrap();
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+
+        let (accepted, peer) = context
+            .ws_listener
+            .as_ref()
+            .unwrap()
+            .accept()
+            .await
+            .unwrap();
+
+        handle(Job::AcceptFromWebSocket(accepted, peer), context)
+            .await
+            .unwrap();
+
+        let (mut socket, _) = tokio_tungstenite::client_async(format!("ws://{addr}"), stream)
+            .await
+            .unwrap();
+
+        let hello = Incoming::TcpHello {
+            version: PROTOCOL_VERSION,
+            capabilities: 0,
+            token: token.into(),
+        };
+
+        socket
+            .send(Message::Binary(hello.serilaize().unwrap()))
+            .await
+            .unwrap();
+
+        testing::settle(context).await;
+
+        socket
+    }
+
+    /// Every binary message that arrived so far besides pings.
+    async fn received_over_websocket(socket: &mut WebSocketStream<TcpStream>) -> Vec<Outgoing> {
+        let mut packets = Vec::new();
+
+        while let Ok(Some(Ok(message))) =
+            time::timeout(Duration::from_millis(20), socket.next()).await
+        {
+            if let Message::Binary(buf) = message {
+                let packet = Outgoing::deserialize(&buf).unwrap();
+
+                if !matches!(packet, Outgoing::Ping { .. }) {
+                    packets.push(packet);
+                }
+            }
+        }
+
+        packets
+    }
+
+    #[tokio::test]
+    async fn websocket_player_meets_a_udp_player() {
+        let mut context = testing::context().await;
+
+        let (mut alice, alice_udp) = bind(&mut context, "alice-token").await;
+
+        let mut bob = login_over_websocket(&mut context, "bob-token").await;
+
+        let received = received_over_websocket(&mut bob).await;
+
+        assert!(matches!(received[0], Outgoing::HelloFromTcp { .. }));
+
+        assert!(received.contains(&Outgoing::Introduce {
+            ids: vec!["alice".into()]
+        }));
+
+        assert!(alice
+            .received()
+            .await
+            .contains(&Outgoing::Welcome { id: "bob".into() }));
+
+        let origin = Vector3 {
+            x: 3.0,
+            y: 0.0,
+            z: -1.0,
+        };
+
+        alice_udp.send(2, Incoming::UpdateOrigin { origin }).await;
+
+        testing::settle(&mut context).await;
+
+        let seen = received_over_websocket(&mut bob)
+            .await
+            .into_iter()
+            .any(|packet| match packet {
+                Outgoing::Snapshot { entities, .. } => entities
+                    .iter()
+                    .any(|entity| entity.id == "alice" && entity.origin == Some(origin)),
+                _ => false,
+            });
+
+        assert!(seen, "bob never saw alice move");
+    }
+
+    fn hello_timeouts(ms: u64) -> ServerConfig {
+        ServerConfig {
+            tcp_hello_timeout_ms: ms,
+            udp_hello_timeout_ms: ms,
+            ..ServerConfig::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connection_without_a_player_is_dropped_at_the_tcp_deadline() {
+        let mut context = testing::context_with(hello_timeouts(500)).await;
+
+        context.auth_provider = Arc::new(Stalled);
+
+        let mut client = testing::connect(&mut context).await;
+
+        let connection_id = *context.connections.keys().next().unwrap();
+
+        client
+            .send(Incoming::TcpHello {
+                version: PROTOCOL_VERSION,
+                capabilities: 0,
+                token: "alice-token".into(),
+            })
+            .await;
+
+        testing::run_for(&mut context, Duration::from_millis(400)).await;
+
+        assert!(client.received().await.is_empty());
+
+        assert!(!client.is_closed());
+
+        testing::run_for(&mut context, Duration::from_millis(200)).await;
+
+        assert!(matches!(
+            client.received().await[..],
+            [Outgoing::Disconnect {
+                reason: DisconnectReason::HandshakeTimeout,
+                ..
+            }]
+        ));
+
+        assert!(client.is_closed());
+
+        assert!(!context.connections.contains_key(&connection_id));
+
+        // The token of the hello only checks out now.
+        let pending = PendingAuth::Tcp {
+            connection_id,
+            version: PROTOCOL_VERSION,
+            capabilities: 0,
+        };
+
+        let response = AuthResponse {
+            id: "alice".into(),
+            timestamp: String::new(),
+        };
+
+        handle(Job::AuthCompleted(pending, Ok(response)), &mut context)
+            .await
+            .unwrap();
+
+        testing::settle(&mut context).await;
+
+        assert!(context.tcp_ids.get_by_key(&"alice".to_owned()).is_none());
+
+        assert!(context.player("alice").is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn player_without_udp_is_dropped_at_the_udp_deadline() {
+        let mut context = testing::context_with(hello_timeouts(500)).await;
+
+        let mut client = testing::login(&mut context, "alice-token", 0).await;
+
+        let received = client.received().await;
+
+        let udp = testing::UdpClient::new(&context, &received[0]).await;
+
+        testing::run_for(&mut context, Duration::from_millis(300)).await;
+
+        assert!(client.received().await.is_empty());
+
+        assert!(!client.is_closed());
+
+        testing::run_for(&mut context, Duration::from_millis(200)).await;
+
+        assert!(matches!(
+            client.received().await[..],
+            [Outgoing::Disconnect {
+                reason: DisconnectReason::UdpHelloTimeout,
+                ..
+            }]
+        ));
+
+        assert!(client.is_closed());
+
+        udp.send(
+            1,
+            Incoming::UdpHello {
+                token: "alice-token".into(),
+            },
+        )
+        .await;
+
+        testing::settle(&mut context).await;
+
+        assert!(udp.received().await.is_empty());
+
+        assert_eq!(addr_of(&context, "alice"), None);
+
+        assert!(context.session("alice").is_none());
+
+        assert!(context.player("alice").is_none());
+    }
+}
@@ -1,11 +1,17 @@
 pub mod env;
 
+pub mod config;
+
 mod math;
 
 mod collection;
 
 mod net;
 
+mod connection;
+
+mod websocket;
+
 mod context;
 
 pub use context::Context;
@@ -14,24 +20,43 @@ pub mod selector;
 
 pub mod job_handler;
 
-mod incoming_handler_from_tcp;
+pub mod handler;
+
+mod handshake_handler;
 
-mod incoming_handler_from_udp;
+pub mod auth;
 
-mod incoming_handler_from_waitings;
+mod player;
+
+mod player_handler;
+
+mod heartbeat_handler;
 
 mod schedule;
 
 mod schedule_queue;
 
-mod readable;
-
 mod job;
 
+pub mod protocol;
+
+mod reliable;
+
+mod session;
+
+mod heartbeat;
+
+mod snapshot;
+
+mod tick;
+
+pub mod shutdown;
+
 mod incoming_packet;
 
 mod outgoing_packet;
 
-mod url;
-
 mod http_response;
+
+#[cfg(test)]
+mod testing;
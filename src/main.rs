@@ -1,23 +1,54 @@
-use std::error::Error;
+use std::{error::Error, process};
 
-use jumong_server::{env, job_handler, selector, Context};
+use jumong_server::{
+    auth,
+    config::ServerConfig,
+    env, job_handler, selector,
+    shutdown::{self, Signals},
+    Context,
+};
 use tokio::net::{TcpListener, UdpSocket};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     env::init();
 
-    let tcp_listener = TcpListener::bind("0.0.0.0:3000").await?;
+    let loaded = ServerConfig::load()
+        .and_then(|config| auth::provider(&config).map(|provider| (config, provider)));
 
-    let udp_socket = UdpSocket::bind("0.0.0.0:3000").await?;
+    let (config, auth_provider) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprint!("{e}");
 
-    let mut context = Context::new(tcp_listener, udp_socket);
+            process::exit(1);
+        }
+    };
+
+    let tcp_listener = TcpListener::bind(config.tcp_addr).await?;
+
+    let ws_listener = TcpListener::bind(config.ws_addr).await?;
+
+    let udp_socket = UdpSocket::bind(config.udp_addr).await?;
+
+    let shutdown_timeout = config.shutdown_timeout();
+
+    let mut context = Context::new(config, auth_provider, tcp_listener, ws_listener, udp_socket);
+
+    let mut signals = Signals::new()?;
 
     loop {
-        let job = selector::select_job(&mut context).await;
+        let job = tokio::select! {
+            job = selector::select_job(&mut context) => job,
+            _ = signals.recv() => break,
+        };
 
         if let Err(e) = job_handler::handle(job, &mut context).await {
             eprintln!("job failed for {e}");
         }
     }
+
+    shutdown::drain(&mut context, shutdown_timeout).await;
+
+    Ok(())
 }
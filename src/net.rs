@@ -1,47 +1,356 @@
-use std::io;
+use std::error::Error;
 
-use tokio::net::TcpStream;
+/// A frame length that announces an extended header once compression is
+/// negotiated.
+const EXTENDED: u16 = u16::MAX;
 
-pub trait Reader {
-    fn try_read_packet_buf(&self, buf: &mut [u8]) -> io::Result<usize>;
+/// The short length, then `[flags u8][len u32]`.
+const EXTENDED_HEADER_LEN: usize = 2 + 1 + 4;
+
+/// Set in the flags of an extended frame whose body is LZ4 compressed.
+const COMPRESSED: u8 = 1 << 0;
+
+const COMPRESSION_THRESHOLD: usize = 512;
+
+const MAX_DECOMPRESSED_LEN: usize = 1024 * 1024;
+
+struct Header {
+    size: usize,
+    len: usize,
+    compressed: bool,
 }
 
-impl Reader for TcpStream {
-    fn try_read_packet_buf(&self, buf: &mut [u8]) -> io::Result<usize> {
-        let n = self.try_read(&mut buf[..2])?;
+/// Reassembles length-prefixed frames out of a byte stream.
+///
+/// Bytes are accumulated across any number of reads, so a frame split by the
+/// kernel is yielded once it is complete and several frames merged into one
+/// read are yielded one by one. Incomplete bytes are kept for the next read.
+///
+/// Once compression is negotiated a length of `u16::MAX` is followed by an
+/// extended header, `[flags u8][len u32]`, which lifts the frame limit to
+/// `MAX_DECOMPRESSED_LEN` and flags LZ4 compressed frames, which are
+/// decompressed before they are yielded. Shorter frames keep the plain
+/// header.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    compression: bool,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        FrameDecoder {
+            buf: Vec::new(),
+            compression: false,
+        }
+    }
+
+    pub fn enable_compression(&mut self) {
+        self.compression = true;
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn next_frame(&mut self) -> Option<Result<Vec<u8>, Box<dyn Error + Sync + Send>>> {
+        let header = match self.header()? {
+            Ok(header) => header,
+            Err(e) => return Some(Err(e)),
+        };
+
+        if self.buf.len() < header.size + header.len {
+            return None;
+        }
+
+        let frame: Vec<u8> = self
+            .buf
+            .drain(..header.size + header.len)
+            .skip(header.size)
+            .collect();
 
-        if n == 0 {
-            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        if !header.compressed {
+            return Some(Ok(frame));
         }
 
-        if n < 2 {
-            return Err(io::Error::new(io::ErrorKind::Other, "buffer too short"));
+        Some(decompress(&frame))
+    }
+
+    /// Reads the header once all of it has arrived.
+    fn header(&self) -> Option<Result<Header, Box<dyn Error + Sync + Send>>> {
+        if self.buf.len() < 2 {
+            return None;
         }
 
-        let len = usize::from(u16::from_le_bytes([buf[0], buf[1]]));
+        let len = u16::from_le_bytes([self.buf[0], self.buf[1]]);
+
+        if !self.compression || len != EXTENDED {
+            return Some(Ok(Header {
+                size: 2,
+                len: usize::from(len),
+                compressed: false,
+            }));
+        }
 
-        if len > buf.len() {
-            return Err(io::Error::new(io::ErrorKind::Other, "packet too big"));
+        if self.buf.len() < EXTENDED_HEADER_LEN {
+            return None;
         }
 
-        let n = self.try_read(&mut buf[..len])?;
+        let flags = self.buf[2];
 
-        if n == 0 {
-            return Err(io::Error::new(io::ErrorKind::Other, "unexpected eof"));
+        let len = u32::from_le_bytes([self.buf[3], self.buf[4], self.buf[5], self.buf[6]]);
+
+        if flags & !COMPRESSED != 0 {
+            return Some(Err(format!("unknown frame flags, {flags:#x}").into()));
+        }
+
+        match usize::try_from(len) {
+            Ok(len) if len <= MAX_DECOMPRESSED_LEN => Some(Ok(Header {
+                size: EXTENDED_HEADER_LEN,
+                len,
+                compressed: flags & COMPRESSED != 0,
+            })),
+            _ => Some(Err(format!("frame too big, {len} bytes").into())),
         }
+    }
+}
+
+fn decompress(frame: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Sync + Send>> {
+    if frame.len() < 4 {
+        return Err(format!("compressed frame too short, {frame:?}").into());
+    }
+
+    let len = usize::try_from(u32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]))?;
+
+    if len > MAX_DECOMPRESSED_LEN {
+        return Err(format!("compressed frame too big, {len} bytes").into());
+    }
+
+    Ok(lz4_flex::block::decompress(&frame[4..], len)?)
+}
 
-        if n != len {
-            return Err(io::Error::new(io::ErrorKind::Other, "length not matched"));
+/// Prefixes `buf` with its length, compressing it first when compression is
+/// negotiated and worth it for its size.
+pub fn wrap_tcp_packet(
+    buf: &[u8],
+    compression: bool,
+) -> Result<Vec<u8>, Box<dyn Error + Sync + Send>> {
+    if !compression {
+        return Ok([&u16::try_from(buf.len())?.to_le_bytes() as &[u8], buf].concat());
+    }
+
+    if buf.len() > MAX_DECOMPRESSED_LEN {
+        return Err(format!("frame too big, {} bytes", buf.len()).into());
+    }
+
+    if buf.len() >= COMPRESSION_THRESHOLD {
+        let compressed = lz4_flex::block::compress_prepend_size(buf);
+
+        if compressed.len() < buf.len() {
+            return Ok(extended_frame(COMPRESSED, &compressed));
         }
+    }
 
-        Ok(n)
+    match u16::try_from(buf.len()) {
+        Ok(len) if len != EXTENDED => Ok([&len.to_le_bytes() as &[u8], buf].concat()),
+        _ => Ok(extended_frame(0, buf)),
     }
 }
 
-pub fn wrap_tcp_packet(buf: &[u8]) -> Vec<u8> {
-    [
-        &u16::try_from(buf.len()).unwrap().to_le_bytes() as &[u8],
-        buf,
-    ]
-    .concat()
+fn extended_frame(flags: u8, body: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(EXTENDED_HEADER_LEN + body.len());
+
+    frame.extend_from_slice(&EXTENDED.to_le_bytes());
+
+    frame.push(flags);
+
+    frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+
+    frame.extend_from_slice(body);
+
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(body: &[u8]) -> Vec<u8> {
+        wrap_tcp_packet(body, false).unwrap()
+    }
+
+    #[test]
+    fn frame_fed_byte_by_byte_is_yielded_once_complete() {
+        let mut decoder = FrameDecoder::new();
+
+        let bytes = frame(b"jumong");
+
+        for byte in &bytes[..bytes.len() - 1] {
+            decoder.extend(&[*byte]);
+
+            assert!(decoder.next_frame().is_none());
+        }
+
+        decoder.extend(&bytes[bytes.len() - 1..]);
+
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"jumong");
+
+        assert!(decoder.next_frame().is_none());
+    }
+
+    #[test]
+    fn coalesced_frames_are_yielded_one_by_one() {
+        let mut decoder = FrameDecoder::new();
+
+        let mut bytes = [frame(b"a"), frame(b""), frame(b"bc")].concat();
+
+        bytes.extend_from_slice(&frame(b"def")[..3]);
+
+        decoder.extend(&bytes);
+
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"a");
+
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"");
+
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"bc");
+
+        assert!(decoder.next_frame().is_none());
+
+        decoder.extend(&frame(b"def")[3..]);
+
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"def");
+    }
+
+    #[test]
+    fn oversize_header_waits_for_its_body() {
+        let mut decoder = FrameDecoder::new();
+
+        decoder.extend(&u16::MAX.to_le_bytes());
+
+        decoder.extend(&[7; 1024]);
+
+        assert!(decoder.next_frame().is_none());
+
+        decoder.extend(&vec![7; usize::from(u16::MAX) - 1024]);
+
+        assert_eq!(
+            decoder.next_frame().unwrap().unwrap().len(),
+            usize::from(u16::MAX)
+        );
+    }
+
+    #[test]
+    fn frame_over_u16_is_refused_without_compression() {
+        assert!(wrap_tcp_packet(&vec![0; 1 << 16], false).is_err());
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Sync + Send>> {
+        let mut decoder = FrameDecoder::new();
+
+        decoder.enable_compression();
+
+        decoder.extend(bytes);
+
+        decoder.next_frame().expect("a whole frame")
+    }
+
+    fn incompressible(len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut buf);
+
+        buf
+    }
+
+    #[test]
+    fn small_frame_keeps_the_plain_header_with_compression() {
+        let frame_with_compression = wrap_tcp_packet(b"jumong", true).unwrap();
+
+        assert_eq!(frame_with_compression, frame(b"jumong"));
+
+        assert_eq!(decode(&frame_with_compression).unwrap(), b"jumong");
+    }
+
+    #[test]
+    fn compressible_frame_round_trips_compressed() {
+        let body = vec![42; 200_000];
+
+        let frame = wrap_tcp_packet(&body, true).unwrap();
+
+        assert_eq!(frame[..3], [0xff, 0xff, COMPRESSED]);
+
+        assert!(frame.len() < body.len());
+
+        assert_eq!(decode(&frame).unwrap(), body);
+    }
+
+    #[test]
+    fn incompressible_frame_over_u16_round_trips_extended() {
+        let body = incompressible(100_000);
+
+        let frame = wrap_tcp_packet(&body, true).unwrap();
+
+        assert_eq!(frame[..3], [0xff, 0xff, 0]);
+
+        assert_eq!(decode(&frame).unwrap(), body);
+    }
+
+    #[test]
+    fn frame_of_u16_max_uses_the_extended_header() {
+        let body = incompressible(usize::from(u16::MAX));
+
+        let frame = wrap_tcp_packet(&body, true).unwrap();
+
+        assert_eq!(frame.len(), EXTENDED_HEADER_LEN + body.len());
+
+        assert_eq!(decode(&frame).unwrap(), body);
+    }
+
+    #[test]
+    fn extended_frame_fed_byte_by_byte_is_yielded_once_complete() {
+        let body = vec![7; 100_000];
+
+        let frame = wrap_tcp_packet(&body, true).unwrap();
+
+        let mut decoder = FrameDecoder::new();
+
+        decoder.enable_compression();
+
+        for byte in &frame[..frame.len() - 1] {
+            decoder.extend(&[*byte]);
+
+            assert!(decoder.next_frame().is_none());
+        }
+
+        decoder.extend(&frame[frame.len() - 1..]);
+
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), body);
+    }
+
+    #[test]
+    fn frame_over_the_limit_is_refused() {
+        assert!(wrap_tcp_packet(&vec![0; MAX_DECOMPRESSED_LEN + 1], true).is_err());
+
+        let mut header = EXTENDED.to_le_bytes().to_vec();
+
+        header.push(0);
+
+        header.extend_from_slice(&(MAX_DECOMPRESSED_LEN as u32 + 1).to_le_bytes());
+
+        assert!(decode(&header).is_err());
+    }
+
+    #[test]
+    fn decompressed_length_over_the_limit_is_refused() {
+        let mut body = (MAX_DECOMPRESSED_LEN as u32 + 1).to_le_bytes().to_vec();
+
+        body.extend_from_slice(&[0; 16]);
+
+        assert!(decode(&extended_frame(COMPRESSED, &body)).is_err());
+    }
+
+    #[test]
+    fn unknown_frame_flags_are_refused() {
+        assert!(decode(&extended_frame(1 << 7, b"x")).is_err());
+    }
 }
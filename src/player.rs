@@ -0,0 +1,125 @@
+use tokio::time;
+
+use crate::{math::Vector3, snapshot::Transform};
+
+/// The world state of a player: where it is, when it last moved and which
+/// snapshot its client acknowledged last. It is kept from the TCP handshake
+/// until the player leaves for good, suspensions included.
+///
+/// How the player is connected is kept apart, in the maps of `Context`
+/// keyed by the same id. A resume, a duplicate login or a NAT rebinding
+/// replaces the session, connection or address under the same player, and
+/// each of them is also looked up the other way, by session id, connection
+/// id or address.
+#[derive(Debug)]
+pub struct Player {
+    pub id: String,
+    pub joined_at: time::Instant,
+    pub origin: Vector3,
+    pub rotation: f32,
+    /// When the player last reported its origin or rotation, if ever.
+    pub updated_at: Option<time::Instant>,
+    /// The latest snapshot the client acknowledged, to delta against it.
+    pub acked_tick: Option<u32>,
+}
+
+impl Player {
+    pub fn new(id: String) -> Self {
+        Player {
+            id,
+            joined_at: time::Instant::now(),
+            origin: Vector3::default(),
+            rotation: 0.0,
+            updated_at: None,
+            acked_tick: None,
+        }
+    }
+
+    pub fn transform(&self) -> Transform {
+        Transform {
+            origin: self.origin,
+            rotation: self.rotation,
+        }
+    }
+
+    pub fn set_origin(&mut self, origin: Vector3) {
+        self.origin = origin;
+
+        self.updated_at = Some(time::Instant::now());
+    }
+
+    pub fn set_rotation(&mut self, rotation: f32) {
+        self.rotation = rotation;
+
+        self.updated_at = Some(time::Instant::now());
+    }
+
+    /// Takes an acknowledgement, ignoring any older than the latest.
+    pub fn ack(&mut self, tick: u32) {
+        self.acked_tick = Some(self.acked_tick.map_or(tick, |acked| acked.max(tick)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_player_has_not_moved() {
+        let player = Player::new("alice".into());
+
+        assert_eq!(player.transform(), Transform::default());
+
+        assert_eq!(player.updated_at, None);
+
+        assert_eq!(player.acked_tick, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn updates_are_timestamped() {
+        let mut player = Player::new("alice".into());
+
+        let origin = Vector3 {
+            x: 1.0,
+            y: 2.0,
+            z: 3.0,
+        };
+
+        player.set_origin(origin);
+
+        assert_eq!(player.origin, origin);
+
+        assert_eq!(player.updated_at, Some(time::Instant::now()));
+
+        time::advance(time::Duration::from_secs(1)).await;
+
+        player.set_rotation(90.0);
+
+        assert_eq!(player.updated_at, Some(time::Instant::now()));
+
+        assert_eq!(
+            player.transform(),
+            Transform {
+                origin,
+                rotation: 90.0,
+            }
+        );
+    }
+
+    #[test]
+    fn ack_keeps_the_latest_tick() {
+        let mut player = Player::new("alice".into());
+
+        player.ack(5);
+
+        assert_eq!(player.acked_tick, Some(5));
+
+        player.ack(3);
+
+        assert_eq!(player.acked_tick, Some(5));
+
+        player.ack(8);
+
+        assert_eq!(player.acked_tick, Some(8));
+    }
+}
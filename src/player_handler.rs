@@ -0,0 +1,66 @@
+use std::error::Error;
+
+use crate::{
+    handler::{Handler, HandlerRegistry, Peer, Request, Transport},
+    incoming_packet::{Incoming, IncomingKind},
+};
+
+pub fn register(registry: &mut HandlerRegistry) {
+    for transport in [Transport::Tcp, Transport::Udp] {
+        registry.register(IncomingKind::UpdateOrigin, transport, UpdateTransform);
+
+        registry.register(IncomingKind::UpdateRotation, transport, UpdateTransform);
+
+        registry.register(IncomingKind::AckSnapshot, transport, AckSnapshot);
+    }
+}
+
+/// Applies the transform a player reports for its own character.
+struct UpdateTransform;
+
+#[async_trait::async_trait]
+impl Handler for UpdateTransform {
+    async fn handle(
+        &self,
+        incoming: Incoming,
+        request: Request<'_>,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let Peer::Player(id, _) = request.peer else {
+            return Err("no player".into());
+        };
+
+        let player = request.context.player_mut(&id).ok_or("no player")?;
+
+        match incoming {
+            Incoming::UpdateOrigin { origin } => player.set_origin(origin),
+            Incoming::UpdateRotation { y } => player.set_rotation(y),
+            _ => return Err("unexpected packet for transform".into()),
+        }
+
+        Ok(())
+    }
+}
+
+/// Records the latest snapshot a player has received, to delta against it.
+struct AckSnapshot;
+
+#[async_trait::async_trait]
+impl Handler for AckSnapshot {
+    async fn handle(
+        &self,
+        incoming: Incoming,
+        request: Request<'_>,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let (Incoming::AckSnapshot { tick }, Peer::Player(id, _)) = (incoming, request.peer) else {
+            return Err("unexpected packet for snapshot ack".into());
+        };
+
+        let context = request.context;
+
+        if tick <= context.tick {
+            context.player_mut(&id).ok_or("no player")?.ack(tick);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,650 @@
+use std::{error::Error, fmt};
+
+use crate::math::Vector3;
+
+pub const PROTOCOL_VERSION: u16 = 1;
+
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+pub const CAPABILITY_COMPRESSION: u32 = 1 << 0;
+
+/// The client wants a resume token to reclaim its session after a dropped
+/// connection.
+pub const CAPABILITY_RESUME: u32 = 1 << 1;
+
+/// Every capability this server understands.
+pub const CAPABILITIES: u32 = CAPABILITY_COMPRESSION | CAPABILITY_RESUME;
+
+/// Selects the highest version both sides speak, given the highest the
+/// client speaks.
+pub fn negotiate_version(version: u16) -> Option<u16> {
+    match version.min(PROTOCOL_VERSION) {
+        version if version >= MIN_PROTOCOL_VERSION => Some(version),
+        _ => None,
+    }
+}
+
+/// Why the server is about to close a connection, sent ahead in
+/// `Outgoing::Disconnect`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    #[default]
+    Shutdown,
+    HandshakeTimeout,
+    UdpHelloTimeout,
+    HeartbeatTimeout,
+    ResumeRejected,
+    Replaced,
+    DuplicateLogin,
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            DisconnectReason::Shutdown => "server is shutting down",
+            DisconnectReason::HandshakeTimeout => "no tcp hello in time",
+            DisconnectReason::UdpHelloTimeout => "no udp hello in time",
+            DisconnectReason::HeartbeatTimeout => "missed too many heartbeats",
+            DisconnectReason::ResumeRejected => "resume token is unknown or expired",
+            DisconnectReason::Replaced => "logged in from another connection",
+            DisconnectReason::DuplicateLogin => "already logged in from another connection",
+        };
+
+        f.write_str(message)
+    }
+}
+
+pub trait Wire: Sized {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Box<dyn Error + Sync + Send>>;
+
+    fn decode(buf: &mut &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>>;
+}
+
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8], Box<dyn Error + Sync + Send>> {
+    if buf.len() < n {
+        return Err(format!("buffer too short to deserialize, {buf:?}").into());
+    }
+
+    let (head, tail) = buf.split_at(n);
+
+    *buf = tail;
+
+    Ok(head)
+}
+
+macro_rules! impl_wire_for_number {
+    ($($ty:ty),*) => {
+        $(
+            impl Wire for $ty {
+                fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Box<dyn Error + Sync + Send>> {
+                    buf.extend_from_slice(&self.to_le_bytes());
+
+                    Ok(())
+                }
+
+                fn decode(buf: &mut &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+                    let bytes = take(buf, std::mem::size_of::<$ty>())?;
+
+                    Ok(<$ty>::from_le_bytes(bytes.try_into()?))
+                }
+            }
+        )*
+    };
+}
+
+impl_wire_for_number!(u8, u16, u32, u64, f32);
+
+impl Wire for bool {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Box<dyn Error + Sync + Send>> {
+        u8::from(*self).encode(buf)
+    }
+
+    fn decode(buf: &mut &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        match u8::decode(buf)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            n => Err(format!("invalid bool, {n}").into()),
+        }
+    }
+}
+
+impl Wire for String {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Box<dyn Error + Sync + Send>> {
+        u16::try_from(self.len())?.encode(buf)?;
+
+        buf.extend_from_slice(self.as_bytes());
+
+        Ok(())
+    }
+
+    fn decode(buf: &mut &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let len = u16::decode(buf)?;
+
+        let bytes = take(buf, usize::from(len))?;
+
+        Ok(String::from_utf8(bytes.to_vec())?)
+    }
+}
+
+impl<T: Wire> Wire for Vec<T> {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Box<dyn Error + Sync + Send>> {
+        u16::try_from(self.len())?.encode(buf)?;
+
+        for item in self {
+            item.encode(buf)?;
+        }
+
+        Ok(())
+    }
+
+    fn decode(buf: &mut &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let len = u16::decode(buf)?;
+
+        (0..len).map(|_| T::decode(buf)).collect()
+    }
+}
+
+impl Wire for Vector3 {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Box<dyn Error + Sync + Send>> {
+        self.x.encode(buf)?;
+
+        self.y.encode(buf)?;
+
+        self.z.encode(buf)
+    }
+
+    fn decode(buf: &mut &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        Ok(Vector3::new(
+            f32::decode(buf)?,
+            f32::decode(buf)?,
+            f32::decode(buf)?,
+        ))
+    }
+}
+
+impl Wire for DisconnectReason {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let n: u8 = match self {
+            DisconnectReason::Shutdown => 1,
+            DisconnectReason::HandshakeTimeout => 2,
+            DisconnectReason::UdpHelloTimeout => 3,
+            DisconnectReason::HeartbeatTimeout => 4,
+            DisconnectReason::ResumeRejected => 5,
+            DisconnectReason::Replaced => 6,
+            DisconnectReason::DuplicateLogin => 7,
+        };
+
+        n.encode(buf)
+    }
+
+    fn decode(buf: &mut &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        match u8::decode(buf)? {
+            1 => Ok(DisconnectReason::Shutdown),
+            2 => Ok(DisconnectReason::HandshakeTimeout),
+            3 => Ok(DisconnectReason::UdpHelloTimeout),
+            4 => Ok(DisconnectReason::HeartbeatTimeout),
+            5 => Ok(DisconnectReason::ResumeRejected),
+            6 => Ok(DisconnectReason::Replaced),
+            7 => Ok(DisconnectReason::DuplicateLogin),
+            n => Err(format!("invalid disconnect reason, {n}").into()),
+        }
+    }
+}
+
+/// Makes values for the generated round-trip tests of [`packets`].
+///
+/// Every field of a packet gets its own seed, so a field that is encoded or
+/// decoded out of order does not come back equal.
+#[cfg(test)]
+pub trait Sample {
+    fn sample(seed: u8) -> Self;
+}
+
+#[cfg(test)]
+mod sample {
+    use super::*;
+
+    impl Sample for u8 {
+        fn sample(seed: u8) -> Self {
+            seed
+        }
+    }
+
+    impl Sample for u16 {
+        fn sample(seed: u8) -> Self {
+            u16::from_le_bytes([seed, 0xa5])
+        }
+    }
+
+    impl Sample for u32 {
+        fn sample(seed: u8) -> Self {
+            u32::from_le_bytes([seed, 0x01, 0x02, 0xa5])
+        }
+    }
+
+    impl Sample for u64 {
+        fn sample(seed: u8) -> Self {
+            u64::from_le_bytes([seed, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0xa5])
+        }
+    }
+
+    impl Sample for f32 {
+        fn sample(seed: u8) -> Self {
+            -(f32::from(seed) + 0.25)
+        }
+    }
+
+    impl Sample for bool {
+        fn sample(seed: u8) -> Self {
+            !seed.is_multiple_of(2)
+        }
+    }
+
+    impl Sample for String {
+        fn sample(seed: u8) -> Self {
+            format!("sample-{seed}")
+        }
+    }
+
+    impl<T: Sample> Sample for Vec<T> {
+        fn sample(seed: u8) -> Self {
+            vec![T::sample(seed), T::sample(seed.wrapping_add(101))]
+        }
+    }
+
+    impl Sample for Vector3 {
+        fn sample(seed: u8) -> Self {
+            let n = f32::from(seed);
+
+            Vector3::new(n, -n, n + 0.5)
+        }
+    }
+
+    impl Sample for DisconnectReason {
+        fn sample(seed: u8) -> Self {
+            match seed % 3 {
+                0 => DisconnectReason::HandshakeTimeout,
+                1 => DisconnectReason::HeartbeatTimeout,
+                _ => DisconnectReason::DuplicateLogin,
+            }
+        }
+    }
+}
+
+/// Packs the messages sent to one peer into as few datagrams as possible.
+///
+/// A datagram from the server is a sequence of messages, each prefixed with
+/// its length as a little-endian `u16`, and never grows past `mtu` bytes
+/// unless a single message is already bigger. Clients split a datagram back
+/// into messages with [`unbatch`].
+pub struct Batch {
+    mtu: usize,
+    datagrams: Vec<Vec<u8>>,
+}
+
+impl Batch {
+    pub fn new(mtu: usize) -> Self {
+        Batch {
+            mtu,
+            datagrams: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, message: &[u8]) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let len = u16::try_from(message.len())?;
+
+        match self.datagrams.last_mut() {
+            Some(datagram) if datagram.len() + 2 + message.len() <= self.mtu => {
+                len.encode(datagram)?;
+
+                datagram.extend_from_slice(message);
+            }
+            _ => {
+                let mut datagram = Vec::with_capacity(self.mtu);
+
+                len.encode(&mut datagram)?;
+
+                datagram.extend_from_slice(message);
+
+                self.datagrams.push(datagram);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn take(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.datagrams)
+    }
+}
+
+pub fn unbatch(datagram: &[u8]) -> Result<Vec<&[u8]>, Box<dyn Error + Sync + Send>> {
+    let mut body = datagram;
+
+    let mut messages = Vec::new();
+
+    while !body.is_empty() {
+        let len = u16::decode(&mut body)?;
+
+        messages.push(take(&mut body, usize::from(len))?);
+    }
+
+    Ok(messages)
+}
+
+/// Whether a packet variant tolerates trailing bytes, see [`packets`].
+macro_rules! extensible {
+    () => {
+        false
+    };
+    (extensible) => {
+        true
+    };
+}
+
+pub(crate) use extensible;
+
+/// Defines a packet enum once and derives its codec from the definition.
+///
+/// Every packet starts with its opcode as a little-endian `u16`, followed by
+/// its fields in declaration order, each encoded through [`Wire`]. The enum
+/// named by `#[kind(..)]` lists the packet types without their fields, to
+/// key tables by packet type.
+///
+/// Trailing bytes after the last field are an error, except for variants
+/// marked `#[extensible]`, which ignore them so that a newer peer may append
+/// fields without breaking an older one.
+macro_rules! packets {
+    (
+        #[kind($kind:ident)]
+        $(#[$meta:meta])*
+        pub enum $name:ident {
+            $(
+                $(#[$attr:ident])?
+                $variant:ident = $opcode:literal {
+                    $($field:ident: $ty:ty),* $(,)?
+                }
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        pub enum $name {
+            $($variant { $($field: $ty),* }),*
+        }
+
+        #[allow(dead_code)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum $kind {
+            $($variant),*
+        }
+
+        #[allow(dead_code)]
+        impl $name {
+            pub fn opcode(&self) -> u16 {
+                match self {
+                    $(Self::$variant { .. } => $opcode),*
+                }
+            }
+
+            pub fn kind(&self) -> $kind {
+                match self {
+                    $(Self::$variant { .. } => $kind::$variant),*
+                }
+            }
+
+            pub fn deserialize(
+                buf: &[u8],
+            ) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+                use $crate::protocol::Wire;
+
+                let mut body = buf;
+
+                let (packet, extensible) = match u16::decode(&mut body)? {
+                    $($opcode => (
+                        Self::$variant {
+                            $($field: <$ty as Wire>::decode(&mut body)?),*
+                        },
+                        $crate::protocol::extensible!($($attr)?),
+                    ),)*
+                    n => return Err(format!("unexpected packet arrived, {n:?}").into()),
+                };
+
+                if !body.is_empty() && !extensible {
+                    return Err(format!("invalid size of body, {buf:?}").into());
+                }
+
+                Ok(packet)
+            }
+
+            pub fn serilaize(&self) -> Result<Vec<u8>, Box<dyn std::error::Error + Sync + Send>> {
+                use $crate::protocol::Wire;
+
+                let mut buf = Vec::new();
+
+                self.opcode().encode(&mut buf)?;
+
+                match self {
+                    $(Self::$variant { $($field),* } => {
+                        $($field.encode(&mut buf)?;)*
+                    })*
+                }
+
+                Ok(buf)
+            }
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            #[test]
+            fn every_packet_round_trips() {
+                use $crate::protocol::Sample;
+
+                let mut seed = 0;
+
+                let packets = vec![
+                    $($name::$variant {
+                        $($field: {
+                            seed += 1;
+
+                            <$ty>::sample(seed)
+                        }),*
+                    }),*
+                ];
+
+                for packet in packets {
+                    let buf = packet.serilaize().unwrap();
+
+                    assert_eq!(buf[..2], packet.opcode().to_le_bytes());
+
+                    assert_eq!($name::deserialize(&buf).unwrap(), packet);
+                }
+            }
+
+            #[test]
+            fn opcodes_are_unique() {
+                let opcodes: &[u16] = &[$($opcode),*];
+
+                for (i, opcode) in opcodes.iter().enumerate() {
+                    assert!(!opcodes[i + 1..].contains(opcode), "duplicated opcode {opcode}");
+                }
+            }
+        }
+    };
+}
+
+pub(crate) use packets;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::incoming_packet::{Incoming, LEGACY_TOKEN_LEN};
+
+    fn round_trip<T: Wire + std::fmt::Debug + PartialEq>(value: T) {
+        let mut buf = Vec::new();
+
+        value.encode(&mut buf).unwrap();
+
+        let mut body = buf.as_slice();
+
+        assert_eq!(T::decode(&mut body).unwrap(), value);
+
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn values_round_trip() {
+        round_trip(42u8);
+        round_trip(4242u16);
+        round_trip(u32::MAX);
+        round_trip(u64::MAX);
+        round_trip(-1.5f32);
+        round_trip(true);
+        round_trip(String::from("jumong"));
+        round_trip(vec![String::from("a"), String::from("bc")]);
+        round_trip(Vector3::new(1.0, -2.0, 3.5));
+    }
+
+    #[test]
+    fn short_buffer_is_rejected() {
+        let mut body: &[u8] = &[5, 0, b'a', b'b'];
+
+        assert!(String::decode(&mut body).is_err());
+    }
+
+    fn hello(version: u16, extra: &[u8]) -> Vec<u8> {
+        let packet = Incoming::TcpHello {
+            version,
+            capabilities: 0,
+            token: String::from("token"),
+        };
+
+        [packet.serilaize().unwrap(), extra.to_vec()].concat()
+    }
+
+    #[test]
+    fn newer_hello_with_more_fields_is_downgraded() {
+        let buf = hello(PROTOCOL_VERSION + 1, &[1, 2, 3]);
+
+        let Incoming::TcpHello { version, token, .. } = Incoming::deserialize(&buf).unwrap() else {
+            panic!("not a tcp hello");
+        };
+
+        assert_eq!(token, "token");
+
+        assert_eq!(negotiate_version(version), Some(PROTOCOL_VERSION));
+    }
+
+    #[test]
+    fn older_hello_is_rejected_by_negotiation() {
+        let buf = hello(MIN_PROTOCOL_VERSION - 1, &[]);
+
+        let Incoming::TcpHello { version, .. } = Incoming::deserialize(&buf).unwrap() else {
+            panic!("not a tcp hello");
+        };
+
+        assert_eq!(negotiate_version(version), None);
+    }
+
+    #[test]
+    fn legacy_hello_is_read_as_version_zero() {
+        let token = "t".repeat(LEGACY_TOKEN_LEN);
+
+        let buf = [&[1, 0], token.as_bytes()].concat();
+
+        assert!(Incoming::deserialize(&buf).is_err());
+
+        let Incoming::TcpHello {
+            version,
+            token: read,
+            ..
+        } = Incoming::deserialize_tcp(&buf).unwrap()
+        else {
+            panic!("not a tcp hello");
+        };
+
+        assert_eq!(read, token);
+
+        assert_eq!(negotiate_version(version), None);
+    }
+
+    #[test]
+    fn trailing_bytes_are_refused_unless_extensible() {
+        let mut buf = Incoming::Pong { sequence: 1 }.serilaize().unwrap();
+
+        buf.push(0);
+
+        assert!(Incoming::deserialize(&buf).is_err());
+    }
+
+    #[test]
+    fn batch_round_trips() {
+        let messages: Vec<Vec<u8>> = vec![b"a".to_vec(), Vec::new(), vec![7; 100]];
+
+        let mut batch = Batch::new(1200);
+
+        for message in &messages {
+            batch.push(message).unwrap();
+        }
+
+        let datagrams = batch.take();
+
+        assert_eq!(datagrams.len(), 1);
+
+        assert_eq!(unbatch(&datagrams[0]).unwrap(), messages);
+
+        assert!(batch.take().is_empty());
+    }
+
+    #[test]
+    fn batch_fills_datagrams_up_to_the_mtu() {
+        let mut batch = Batch::new(100);
+
+        batch.push(&[1; 48]).unwrap();
+
+        batch.push(&[2; 48]).unwrap();
+
+        batch.push(&[3; 1]).unwrap();
+
+        let datagrams = batch.take();
+
+        assert_eq!(datagrams.iter().map(Vec::len).collect::<Vec<_>>(), [100, 3]);
+
+        assert_eq!(unbatch(&datagrams[0]).unwrap(), [&[1; 48][..], &[2; 48]]);
+
+        assert_eq!(unbatch(&datagrams[1]).unwrap(), [&[3; 1][..]]);
+    }
+
+    #[test]
+    fn oversized_message_gets_a_datagram_of_its_own() {
+        let mut batch = Batch::new(100);
+
+        batch.push(&[1; 10]).unwrap();
+
+        batch.push(&[2; 200]).unwrap();
+
+        batch.push(&[3; 10]).unwrap();
+
+        let datagrams = batch.take();
+
+        assert_eq!(
+            datagrams.iter().map(Vec::len).collect::<Vec<_>>(),
+            [12, 202, 12]
+        );
+    }
+
+    #[test]
+    fn truncated_batch_is_rejected() {
+        let mut batch = Batch::new(100);
+
+        batch.push(&[1; 10]).unwrap();
+
+        let datagram = batch.take().remove(0);
+
+        assert!(unbatch(&datagram[..datagram.len() - 1]).is_err());
+
+        assert!(unbatch(&datagram[..1]).is_err());
+    }
+}
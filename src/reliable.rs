@@ -0,0 +1,469 @@
+use std::{collections::HashMap, error::Error, time::Duration};
+
+use crate::protocol::Wire;
+
+/// How long a reliable message waits for its acknowledgement before the
+/// round trip has been measured, and the least it ever waits.
+pub const RESEND_TIMEOUT: Duration = Duration::from_millis(200);
+
+pub const MAX_RESEND_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub const MAX_ATTEMPTS: u32 = 10;
+
+const WINDOW: u16 = 32;
+
+/// The encoded size of a [`Header`].
+pub const HEADER_LEN: usize = 1 + 2 + 2 + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Delivered at most once, in any order.
+    Unreliable,
+    /// Delivered at most once, and dropped if a newer one already arrived.
+    UnreliableSequenced,
+    /// Delivered exactly once and in order, retransmitted until acknowledged.
+    ReliableOrdered,
+}
+
+impl Wire for Delivery {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let n: u8 = match self {
+            Delivery::Unreliable => 0,
+            Delivery::UnreliableSequenced => 1,
+            Delivery::ReliableOrdered => 2,
+        };
+
+        n.encode(buf)
+    }
+
+    fn decode(buf: &mut &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        match u8::decode(buf)? {
+            0 => Ok(Delivery::Unreliable),
+            1 => Ok(Delivery::UnreliableSequenced),
+            2 => Ok(Delivery::ReliableOrdered),
+            n => Err(format!("unexpected delivery, {n}").into()),
+        }
+    }
+}
+
+/// Prefixes every message in both directions.
+///
+/// `sequence` counts per delivery kind and is ignored for unreliable
+/// messages. `ack` is the next reliable sequence the sender expects, so
+/// everything before it has been received, and bit `i` of `ack_bits` marks
+/// `ack + 1 + i` as received out of order. A message with an empty payload
+/// only carries acknowledgements.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub delivery: Delivery,
+    pub sequence: u16,
+    pub ack: u16,
+    pub ack_bits: u32,
+}
+
+impl Wire for Header {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Box<dyn Error + Sync + Send>> {
+        self.delivery.encode(buf)?;
+
+        self.sequence.encode(buf)?;
+
+        self.ack.encode(buf)?;
+
+        self.ack_bits.encode(buf)
+    }
+
+    fn decode(buf: &mut &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        Ok(Header {
+            delivery: Delivery::decode(buf)?,
+            sequence: u16::decode(buf)?,
+            ack: u16::decode(buf)?,
+            ack_bits: u32::decode(buf)?,
+        })
+    }
+}
+
+pub fn is_newer(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) > 0
+}
+
+pub struct Resend {
+    pub message: Vec<u8>,
+    pub timeout: Duration,
+}
+
+struct Pending {
+    payload: Vec<u8>,
+    attempts: u32,
+}
+
+/// Per-peer state of the reliability layer over the UDP socket.
+#[derive(Default)]
+pub struct Channel {
+    next_sequenced: u16,
+    next_reliable: u16,
+    pending: HashMap<u16, Pending>,
+    remote_sequenced: Option<u16>,
+    remote_reliable: u16,
+    buffered: HashMap<u16, Vec<u8>>,
+    resend_timeout: Option<Duration>,
+}
+
+impl Channel {
+    pub fn new() -> Self {
+        Channel::default()
+    }
+
+    /// Bases the resend timeout on the measured round trip, as TCP does with
+    /// SRTT + 4 * RTTVAR, within `RESEND_TIMEOUT` and `MAX_RESEND_TIMEOUT`.
+    pub fn set_rtt(&mut self, rtt: Duration, jitter: Duration) {
+        let timeout = rtt.saturating_add(jitter.saturating_mul(4));
+
+        self.resend_timeout = Some(timeout.clamp(RESEND_TIMEOUT, MAX_RESEND_TIMEOUT));
+    }
+
+    /// How long to wait for the first acknowledgement of a reliable message.
+    pub fn resend_timeout(&self) -> Duration {
+        self.resend_timeout.unwrap_or(RESEND_TIMEOUT)
+    }
+
+    /// Wraps `payload` into a message, returning the reliable sequence to
+    /// retransmit if it is not acknowledged in time.
+    pub fn send(
+        &mut self,
+        delivery: Delivery,
+        payload: Vec<u8>,
+    ) -> Result<(Vec<u8>, Option<u16>), Box<dyn Error + Sync + Send>> {
+        match delivery {
+            Delivery::Unreliable => Ok((self.message(delivery, 0, &payload)?, None)),
+            Delivery::UnreliableSequenced => {
+                let sequence = self.next_sequenced;
+
+                self.next_sequenced = sequence.wrapping_add(1);
+
+                Ok((self.message(delivery, sequence, &payload)?, None))
+            }
+            Delivery::ReliableOrdered => {
+                let sequence = self.next_reliable;
+
+                self.next_reliable = sequence.wrapping_add(1);
+
+                let message = self.message(delivery, sequence, &payload)?;
+
+                self.pending.insert(
+                    sequence,
+                    Pending {
+                        payload,
+                        attempts: 1,
+                    },
+                );
+
+                Ok((message, Some(sequence)))
+            }
+        }
+    }
+
+    /// Rebuilds the message of an unacknowledged reliable sequence with the
+    /// latest acknowledgements, or `None` if it has been acknowledged.
+    pub fn resend(
+        &mut self,
+        sequence: u16,
+    ) -> Result<Option<Resend>, Box<dyn Error + Sync + Send>> {
+        let attempts = match self.pending.get_mut(&sequence) {
+            Some(pending) if pending.attempts >= MAX_ATTEMPTS => {
+                return Err(format!("reliable sequence {sequence} never acknowledged").into())
+            }
+            Some(pending) => {
+                pending.attempts += 1;
+
+                pending.attempts
+            }
+            None => return Ok(None),
+        };
+
+        let payload = &self.pending[&sequence].payload;
+
+        let message = self.message(Delivery::ReliableOrdered, sequence, payload)?;
+
+        let timeout = self
+            .resend_timeout()
+            .saturating_mul(1u32 << (attempts - 1).min(4))
+            .min(MAX_RESEND_TIMEOUT);
+
+        Ok(Some(Resend { message, timeout }))
+    }
+
+    pub fn ack_only(&self) -> Result<Vec<u8>, Box<dyn Error + Sync + Send>> {
+        self.message(Delivery::Unreliable, 0, &[])
+    }
+
+    /// Applies the acknowledgements in `header` and returns the payloads
+    /// that are ready to be handled, in order.
+    pub fn receive(&mut self, header: &Header, payload: &[u8]) -> Vec<Vec<u8>> {
+        self.pending.retain(|sequence, _| {
+            let offset = sequence.wrapping_sub(header.ack);
+
+            let acked = is_newer(header.ack, *sequence)
+                || (1..=WINDOW).contains(&offset) && header.ack_bits & (1 << (offset - 1)) != 0;
+
+            !acked
+        });
+
+        if payload.is_empty() {
+            return Vec::new();
+        }
+
+        match header.delivery {
+            Delivery::Unreliable => vec![payload.to_vec()],
+            Delivery::UnreliableSequenced => match self.remote_sequenced {
+                Some(latest) if !is_newer(header.sequence, latest) => Vec::new(),
+                _ => {
+                    self.remote_sequenced = Some(header.sequence);
+
+                    vec![payload.to_vec()]
+                }
+            },
+            Delivery::ReliableOrdered => {
+                let offset = header.sequence.wrapping_sub(self.remote_reliable);
+
+                if offset == 0 {
+                    let mut payloads = vec![payload.to_vec()];
+
+                    self.remote_reliable = self.remote_reliable.wrapping_add(1);
+
+                    while let Some(payload) = self.buffered.remove(&self.remote_reliable) {
+                        payloads.push(payload);
+
+                        self.remote_reliable = self.remote_reliable.wrapping_add(1);
+                    }
+
+                    payloads
+                } else {
+                    if (1..=WINDOW).contains(&offset) {
+                        self.buffered.insert(header.sequence, payload.to_vec());
+                    }
+
+                    Vec::new()
+                }
+            }
+        }
+    }
+
+    fn message(
+        &self,
+        delivery: Delivery,
+        sequence: u16,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn Error + Sync + Send>> {
+        let ack = self.remote_reliable;
+
+        let ack_bits = (1..=WINDOW)
+            .filter(|offset| self.buffered.contains_key(&ack.wrapping_add(*offset)))
+            .fold(0u32, |bits, offset| bits | 1 << (offset - 1));
+
+        let header = Header {
+            delivery,
+            sequence,
+            ack,
+            ack_bits,
+        };
+
+        let mut buf = Vec::new();
+
+        header.encode(&mut buf)?;
+
+        buf.extend_from_slice(payload);
+
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(delivery: Delivery, sequence: u16) -> Header {
+        Header {
+            delivery,
+            sequence,
+            ack: 0,
+            ack_bits: 0,
+        }
+    }
+
+    fn reliable(channel: &mut Channel, sequence: u16) -> Vec<Vec<u8>> {
+        let header = header(Delivery::ReliableOrdered, sequence);
+
+        channel.receive(&header, &sequence.to_le_bytes())
+    }
+
+    fn payloads(sequences: &[u16]) -> Vec<Vec<u8>> {
+        sequences.iter().map(|s| s.to_le_bytes().to_vec()).collect()
+    }
+
+    fn acks(channel: &Channel) -> Header {
+        let message = channel.ack_only().unwrap();
+
+        Header::decode(&mut message.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn reliable_in_order_is_delivered_right_away() {
+        let mut channel = Channel::new();
+
+        for sequence in 0..3 {
+            assert_eq!(reliable(&mut channel, sequence), payloads(&[sequence]));
+        }
+
+        assert_eq!(acks(&channel).ack, 3);
+    }
+
+    #[test]
+    fn reliable_reordered_waits_for_the_gap() {
+        let mut channel = Channel::new();
+
+        assert!(reliable(&mut channel, 2).is_empty());
+
+        assert!(reliable(&mut channel, 1).is_empty());
+
+        assert_eq!(reliable(&mut channel, 0), payloads(&[0, 1, 2]));
+    }
+
+    #[test]
+    fn reliable_duplicates_are_delivered_once() {
+        let mut channel = Channel::new();
+
+        assert_eq!(reliable(&mut channel, 0), payloads(&[0]));
+
+        assert!(reliable(&mut channel, 0).is_empty());
+
+        assert!(reliable(&mut channel, 2).is_empty());
+
+        assert!(reliable(&mut channel, 2).is_empty());
+
+        assert_eq!(reliable(&mut channel, 1), payloads(&[1, 2]));
+    }
+
+    #[test]
+    fn reliable_sequence_wraps() {
+        let mut channel = Channel::new();
+
+        channel.remote_reliable = u16::MAX;
+
+        assert!(reliable(&mut channel, 0).is_empty());
+
+        assert_eq!(reliable(&mut channel, u16::MAX), payloads(&[u16::MAX, 0]));
+
+        assert_eq!(reliable(&mut channel, 1), payloads(&[1]));
+    }
+
+    #[test]
+    fn reliable_beyond_the_window_is_dropped() {
+        let mut channel = Channel::new();
+
+        assert!(reliable(&mut channel, WINDOW + 1).is_empty());
+
+        assert_eq!(acks(&channel).ack_bits, 0);
+    }
+
+    #[test]
+    fn sequenced_drops_older_and_wraps() {
+        let mut channel = Channel::new();
+
+        let mut sequenced = |sequence: u16| {
+            let header = header(Delivery::UnreliableSequenced, sequence);
+
+            channel.receive(&header, b"x").len()
+        };
+
+        assert_eq!(sequenced(u16::MAX - 1), 1);
+
+        assert_eq!(sequenced(u16::MAX - 2), 0);
+
+        assert_eq!(sequenced(u16::MAX - 1), 0);
+
+        assert_eq!(sequenced(0), 1);
+    }
+
+    #[test]
+    fn ack_bits_mark_out_of_order_sequences() {
+        let mut channel = Channel::new();
+
+        reliable(&mut channel, 1);
+
+        reliable(&mut channel, 3);
+
+        let header = acks(&channel);
+
+        assert_eq!(header.ack, 0);
+
+        assert_eq!(header.ack_bits, 0b101);
+    }
+
+    #[test]
+    fn acknowledged_messages_are_not_resent() {
+        let mut channel = Channel::new();
+
+        for _ in 0..3 {
+            channel.send(Delivery::ReliableOrdered, vec![1]).unwrap();
+        }
+
+        let ack = Header {
+            delivery: Delivery::Unreliable,
+            sequence: 0,
+            ack: 1,
+            ack_bits: 0b1,
+        };
+
+        channel.receive(&ack, &[]);
+
+        assert!(channel.resend(0).unwrap().is_none());
+
+        assert!(channel.resend(1).unwrap().is_some());
+
+        assert!(channel.resend(2).unwrap().is_none());
+    }
+
+    #[test]
+    fn resend_backs_off_and_gives_up() {
+        let mut channel = Channel::new();
+
+        let (_, sequence) = channel.send(Delivery::ReliableOrdered, vec![1]).unwrap();
+
+        let sequence = sequence.unwrap();
+
+        let timeouts: Vec<_> = (1..MAX_ATTEMPTS)
+            .map(|_| channel.resend(sequence).unwrap().unwrap().timeout)
+            .collect();
+
+        assert_eq!(timeouts[0], RESEND_TIMEOUT * 2);
+
+        assert_eq!(*timeouts.last().unwrap(), MAX_RESEND_TIMEOUT);
+
+        assert!(channel.resend(sequence).is_err());
+    }
+
+    #[test]
+    fn resend_timeout_follows_the_round_trip() {
+        let mut channel = Channel::new();
+
+        assert_eq!(channel.resend_timeout(), RESEND_TIMEOUT);
+
+        channel.set_rtt(Duration::from_millis(300), Duration::from_millis(50));
+
+        assert_eq!(channel.resend_timeout(), Duration::from_millis(500));
+
+        let (_, sequence) = channel.send(Delivery::ReliableOrdered, vec![1]).unwrap();
+
+        let resend = channel.resend(sequence.unwrap()).unwrap().unwrap();
+
+        assert_eq!(resend.timeout, Duration::from_secs(1));
+
+        channel.set_rtt(Duration::from_millis(1), Duration::ZERO);
+
+        assert_eq!(channel.resend_timeout(), RESEND_TIMEOUT);
+
+        channel.set_rtt(Duration::from_secs(5), Duration::ZERO);
+
+        assert_eq!(channel.resend_timeout(), MAX_RESEND_TIMEOUT);
+    }
+}
@@ -1,23 +1,69 @@
+use std::{sync::Arc, time::Duration};
+
 use tokio::time;
 
 pub struct Schedule<T> {
     pub job: T,
     pub deadline: time::Instant,
+    pub(crate) id: u64,
+    pub(crate) sequence: u64,
+    pub(crate) recurrence: Option<Recurrence<T>>,
+}
+
+/// How a recurring schedule makes its next job.
+pub(crate) struct Recurrence<T> {
+    pub interval: Duration,
+    pub make: Arc<dyn Fn() -> T + Send + Sync>,
+}
+
+impl<T> Clone for Recurrence<T> {
+    fn clone(&self) -> Self {
+        Recurrence {
+            interval: self.interval,
+            make: self.make.clone(),
+        }
+    }
 }
 
 impl<T> Schedule<T> {
     pub fn new(job: T, deadline: time::Instant) -> Self {
-        Schedule { job, deadline }
+        Schedule {
+            job,
+            deadline,
+            id: 0,
+            sequence: 0,
+            recurrence: None,
+        }
     }
 
     pub fn instant(job: T) -> Self {
         Schedule::new(job, time::Instant::now())
     }
+
+    /// Runs the job made by `make` every `interval`, the first time one
+    /// `interval` from now, until the schedule is cancelled.
+    pub fn every<F>(interval: Duration, make: F) -> Self
+    where
+        F: Fn() -> T + Send + Sync + 'static,
+    {
+        let mut schedule = Schedule::new(make(), time::Instant::now() + interval);
+
+        schedule.recurrence = Some(Recurrence {
+            interval,
+            make: Arc::new(make),
+        });
+
+        schedule
+    }
+
+    pub fn is_recurring(&self) -> bool {
+        self.recurrence.is_some()
+    }
 }
 
 impl<T> PartialEq for Schedule<T> {
     fn eq(&self, other: &Self) -> bool {
-        self.deadline == other.deadline
+        self.deadline == other.deadline && self.sequence == other.sequence
     }
 }
 
@@ -25,14 +71,15 @@ impl<T> Eq for Schedule<T> {}
 
 impl<T> PartialOrd for Schedule<T> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.deadline
-            .partial_cmp(&other.deadline)
-            .map(|ordering| ordering.reverse())
+        Some(self.cmp(other))
     }
 }
 
 impl<T> Ord for Schedule<T> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.deadline.cmp(&other.deadline).reverse()
+        self.deadline
+            .cmp(&other.deadline)
+            .then(self.sequence.cmp(&other.sequence))
+            .reverse()
     }
 }
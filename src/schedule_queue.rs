@@ -1,54 +1,280 @@
-use std::collections::BinaryHeap;
+use std::collections::{BinaryHeap, HashSet};
 use std::error::Error;
 
 use tokio::time;
 
 use crate::schedule::Schedule;
 
-#[async_trait::async_trait]
-pub trait ScheduleQueue<T>
-where
-    T: Sync + Send,
-{
-    fn new() -> Self;
+/// Refers to a pushed schedule, to cancel it later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ScheduleHandle(u64);
 
-    fn is_first_urgent(&self) -> bool;
+/// Schedules ordered by deadline, and by push order for equal deadlines.
+///
+/// A cancelled schedule stays in the heap until it reaches the top, where it
+/// is discarded, so the first schedule is always one that will run.
+pub struct ScheduleQueue<T> {
+    heap: BinaryHeap<Schedule<T>>,
+    live: HashSet<u64>,
+    next_sequence: u64,
+}
 
-    async fn wait_for_first(&self) -> Result<(), Box<dyn Error>>;
+impl<T> Default for ScheduleQueue<T> {
+    fn default() -> Self {
+        ScheduleQueue {
+            heap: BinaryHeap::new(),
+            live: HashSet::new(),
+            next_sequence: 0,
+        }
+    }
 }
 
-#[async_trait::async_trait]
-impl<T> ScheduleQueue<T> for BinaryHeap<Schedule<T>>
-where
-    T: Sync + Send,
-{
-    fn new() -> Self {
-        BinaryHeap::new()
+impl<T> ScheduleQueue<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, mut schedule: Schedule<T>) -> ScheduleHandle {
+        let id = self.next_sequence;
+
+        schedule.id = id;
+
+        self.enqueue(schedule);
+
+        self.live.insert(id);
+
+        ScheduleHandle(id)
+    }
+
+    /// Keeps the schedule from running again. Cancelling one that already ran
+    /// does nothing.
+    pub fn cancel(&mut self, handle: ScheduleHandle) {
+        self.live.remove(&handle.0);
+
+        self.discard_cancelled();
+    }
+
+    /// Takes the first schedule, arming the next run of a recurring one.
+    pub fn pop(&mut self) -> Option<Schedule<T>> {
+        let schedule = self.heap.pop()?;
+
+        match &schedule.recurrence {
+            Some(recurrence) => {
+                let mut next =
+                    Schedule::new((recurrence.make)(), schedule.deadline + recurrence.interval);
+
+                next.id = schedule.id;
+
+                next.recurrence = Some(recurrence.clone());
+
+                self.enqueue(next);
+            }
+            None => {
+                self.live.remove(&schedule.id);
+            }
+        }
+
+        self.discard_cancelled();
+
+        Some(schedule)
+    }
+
+    /// Takes every schedule in order without arming any recurrence, leaving
+    /// the queue empty.
+    pub fn drain(&mut self) -> Vec<Schedule<T>> {
+        let mut schedules = std::mem::take(&mut self.heap).into_sorted_vec();
+
+        schedules.reverse();
+
+        schedules.retain(|schedule| self.live.contains(&schedule.id));
+
+        self.live.clear();
+
+        schedules
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
     }
 
-    fn is_first_urgent(&self) -> bool {
+    pub fn is_first_urgent(&self) -> bool {
         if self.is_empty() {
             return false;
         }
 
-        let first_schedule = self.peek().unwrap();
+        let first_schedule = self.heap.peek().unwrap();
 
         if first_schedule.deadline > time::Instant::now() {
             return false;
         }
 
-        return true;
+        true
     }
 
-    async fn wait_for_first(&self) -> Result<(), Box<dyn Error>> {
+    pub async fn wait_for_first(&self) -> Result<(), Box<dyn Error>> {
         if self.is_empty() {
             return Err("no schedule".into());
         }
 
-        let first_schedule = self.peek().unwrap();
+        let first_schedule = self.heap.peek().unwrap();
 
         time::sleep_until(first_schedule.deadline).await;
 
         Ok(())
     }
+
+    fn enqueue(&mut self, mut schedule: Schedule<T>) {
+        schedule.sequence = self.next_sequence;
+
+        self.next_sequence += 1;
+
+        self.heap.push(schedule);
+    }
+
+    fn discard_cancelled(&mut self) {
+        while let Some(first_schedule) = self.heap.peek() {
+            if self.live.contains(&first_schedule.id) {
+                break;
+            }
+
+            self.heap.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn jobs(queue: &mut ScheduleQueue<u32>) -> Vec<u32> {
+        std::iter::from_fn(|| queue.pop().map(|schedule| schedule.job)).collect()
+    }
+
+    #[test]
+    fn pops_by_deadline() {
+        let now = time::Instant::now();
+
+        let mut queue = ScheduleQueue::new();
+
+        queue.push(Schedule::new(2, now + Duration::from_secs(2)));
+
+        queue.push(Schedule::new(0, now));
+
+        queue.push(Schedule::new(1, now + Duration::from_secs(1)));
+
+        assert_eq!(jobs(&mut queue), [0, 1, 2]);
+    }
+
+    #[test]
+    fn equal_deadlines_pop_in_push_order() {
+        let now = time::Instant::now();
+
+        let mut queue = ScheduleQueue::new();
+
+        for job in 0..8 {
+            queue.push(Schedule::new(job, now));
+        }
+
+        assert_eq!(jobs(&mut queue), [0, 1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn cancelled_schedule_never_pops() {
+        let now = time::Instant::now();
+
+        let mut queue = ScheduleQueue::new();
+
+        let first = queue.push(Schedule::new(0, now));
+
+        let buried = queue.push(Schedule::new(1, now + Duration::from_secs(1)));
+
+        queue.push(Schedule::new(2, now + Duration::from_secs(2)));
+
+        queue.cancel(buried);
+
+        assert_eq!(queue.pop().map(|schedule| schedule.job), Some(0));
+
+        assert_eq!(jobs(&mut queue), [2]);
+
+        assert!(queue.is_empty());
+
+        // Cancelling a schedule that already ran does nothing.
+        queue.cancel(first);
+
+        queue.push(Schedule::new(3, now));
+
+        assert_eq!(jobs(&mut queue), [3]);
+    }
+
+    #[test]
+    fn cancelled_first_schedule_is_discarded() {
+        let now = time::Instant::now();
+
+        let mut queue = ScheduleQueue::new();
+
+        let first = queue.push(Schedule::new(0, now + Duration::from_secs(60)));
+
+        queue.cancel(first);
+
+        assert!(queue.is_empty());
+
+        assert!(!queue.is_first_urgent());
+    }
+
+    #[test]
+    fn recurring_schedule_rearms_until_cancelled() {
+        let interval = Duration::from_secs(1);
+
+        let mut queue = ScheduleQueue::new();
+
+        let handle = queue.push(Schedule::every(interval, || 7));
+
+        let first = queue.pop().unwrap();
+
+        assert!(first.is_recurring());
+
+        let second = queue.pop().unwrap();
+
+        assert_eq!(second.job, 7);
+
+        assert_eq!(second.deadline, first.deadline + interval);
+
+        assert_eq!(queue.pop().unwrap().deadline, first.deadline + interval * 2);
+
+        queue.cancel(handle);
+
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn drain_skips_cancelled_and_does_not_rearm() {
+        let now = time::Instant::now();
+
+        let mut queue = ScheduleQueue::new();
+
+        queue.push(Schedule::new(1, now + Duration::from_secs(1)));
+
+        let cancelled = queue.push(Schedule::new(2, now + Duration::from_secs(2)));
+
+        queue.push(Schedule::new(0, now));
+
+        queue.push(Schedule::every(Duration::from_secs(3), || 3));
+
+        queue.push(Schedule::new(4, now + Duration::from_secs(4)));
+
+        queue.cancel(cancelled);
+
+        let drained = queue.drain();
+
+        let drained = drained
+            .iter()
+            .map(|schedule| schedule.job)
+            .collect::<Vec<_>>();
+
+        assert_eq!(drained, [0, 1, 3, 4]);
+
+        assert!(queue.is_empty());
+    }
 }
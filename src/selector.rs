@@ -1,19 +1,30 @@
-use crate::{job::Job, readable::Readable, schedule_queue::ScheduleQueue, Context};
+use std::{future, net::SocketAddr};
+
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{job::Job, Context};
 
 pub async fn select_job(context: &mut Context) -> Job {
     if context.schedule_queue.is_first_urgent() {
         return context.schedule_queue.pop().unwrap().job;
     }
 
+    if !context.udp_batches.is_empty() {
+        return Job::FlushToUdp;
+    }
+
     tokio::select! {
-        Ok((stream, addr)) = context.tcp_listener.accept() => {
+        Some((stream, addr)) = accept(&context.tcp_listener) => {
             Job::AcceptFromTcp(stream, addr)
         }
-        Ok(i) = context.waitings.readable() => {
-            Job::ReadableFromWaiting(i)
+        Some((stream, addr)) = accept(&context.ws_listener) => {
+            Job::AcceptFromWebSocket(stream, addr)
         }
-        Ok(addr) = context.tcp_streams.readable() => {
-            Job::ReadableFromTcp(addr)
+        Some((id, incoming)) = context.incoming_receiver.recv() => {
+            Job::IncomingFromTcp(id, incoming)
+        }
+        Some(job) = context.job_receiver.recv() => {
+            job
         }
         Ok(_) = context.udp_socket.readable() => {
             Job::ReadableFromUdp
@@ -23,3 +34,10 @@ pub async fn select_job(context: &mut Context) -> Job {
         },
     }
 }
+
+async fn accept(listener: &Option<TcpListener>) -> Option<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await.ok(),
+        None => future::pending().await,
+    }
+}
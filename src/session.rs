@@ -0,0 +1,357 @@
+use std::{error::Error, net::SocketAddr};
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+use crate::{handler::Transport, heartbeat::Heartbeat};
+
+pub const KEY_LEN: usize = 32;
+
+pub const MAC_LEN: usize = 16;
+
+const RESUME_SECRET_LEN: usize = 32;
+
+/// A resume token is the session id followed by a random secret, so the
+/// session is looked up without comparing the token to every other.
+pub const RESUME_TOKEN_LEN: usize = 4 + RESUME_SECRET_LEN;
+
+const NONCE_WINDOW: u64 = 64;
+
+/// Credentials issued by `HelloFromTcp` to authenticate UDP datagrams.
+///
+/// Every datagram from the client is laid out as
+/// `[session id u32][nonce u64][body][mac]`, where the MAC is an
+/// HMAC-SHA256 of everything before it keyed with the session secret and
+/// truncated to `MAC_LEN` bytes. Nonces must grow; a nonce is accepted once
+/// and only if it is within `NONCE_WINDOW` of the latest one.
+pub struct Session {
+    pub id: String,
+    pub session_id: u32,
+    pub version: u16,
+    pub capabilities: u32,
+    key: [u8; KEY_LEN],
+    latest_nonce: Option<u64>,
+    nonce_bits: u64,
+    resume_secret: [u8; RESUME_SECRET_LEN],
+    pub tcp_heartbeat: Heartbeat,
+    pub udp_heartbeat: Heartbeat,
+    /// A new address the client seems to have moved to, and the `Ping`
+    /// sequence it has to echo back from there before it is bound.
+    pub path_probe: Option<(SocketAddr, u32)>,
+    /// Whether the token of a `UdpHello` is being checked. Other hellos are
+    /// ignored until the result is back.
+    pub udp_authenticating: bool,
+}
+
+impl Session {
+    pub fn new(id: String, session_id: u32, version: u16, capabilities: u32) -> Self {
+        let mut key = [0; KEY_LEN];
+
+        rand::thread_rng().fill_bytes(&mut key);
+
+        let mut resume_secret = [0; RESUME_SECRET_LEN];
+
+        rand::thread_rng().fill_bytes(&mut resume_secret);
+
+        Session {
+            id,
+            session_id,
+            version,
+            capabilities,
+            key,
+            latest_nonce: None,
+            nonce_bits: 0,
+            resume_secret,
+            tcp_heartbeat: Heartbeat::new(),
+            udp_heartbeat: Heartbeat::new(),
+            path_probe: None,
+            udp_authenticating: false,
+        }
+    }
+
+    pub fn heartbeat_mut(&mut self, transport: Transport) -> &mut Heartbeat {
+        match transport {
+            Transport::Tcp => &mut self.tcp_heartbeat,
+            Transport::Udp => &mut self.udp_heartbeat,
+        }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn resume_token(&self) -> Vec<u8> {
+        let mut token = self.session_id.to_le_bytes().to_vec();
+
+        token.extend_from_slice(&self.resume_secret);
+
+        token
+    }
+
+    /// Whether `token` is the current resume token, compared in constant
+    /// time.
+    pub fn verify_resume_token(&self, token: &[u8]) -> bool {
+        token.len() == RESUME_TOKEN_LEN
+            && resume_session_id(token) == Some(self.session_id)
+            && bool::from(token[4..].ct_eq(&self.resume_secret))
+    }
+
+    /// Replaces the resume token, so each one reclaims the session once.
+    pub fn rotate_resume_token(&mut self) {
+        rand::thread_rng().fill_bytes(&mut self.resume_secret);
+    }
+
+    /// Verifies the MAC and the nonce of `datagram` and returns its body,
+    /// along with whether it carries the highest nonce seen so far.
+    pub fn open<'a>(
+        &mut self,
+        datagram: &'a [u8],
+    ) -> Result<(&'a [u8], bool), Box<dyn Error + Sync + Send>> {
+        if datagram.len() < 12 + MAC_LEN {
+            return Err(format!("datagram too short to open, {datagram:?}").into());
+        }
+
+        let (signed, tag) = datagram.split_at(datagram.len() - MAC_LEN);
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key)?;
+
+        mac.update(signed);
+
+        mac.verify_truncated_left(tag)
+            .map_err(|_| "datagram with invalid mac")?;
+
+        let nonce = u64::from_le_bytes(signed[4..12].try_into()?);
+
+        let newest = self.latest_nonce.is_none_or(|latest| nonce > latest);
+
+        self.accept_nonce(nonce)?;
+
+        Ok((&signed[12..], newest))
+    }
+
+    fn accept_nonce(&mut self, nonce: u64) -> Result<(), Box<dyn Error + Sync + Send>> {
+        match self.latest_nonce {
+            Some(latest) if nonce > latest => {
+                let shift = nonce - latest;
+
+                self.nonce_bits = if shift >= NONCE_WINDOW {
+                    0
+                } else {
+                    self.nonce_bits << shift
+                };
+
+                self.nonce_bits |= 1;
+
+                self.latest_nonce = Some(nonce);
+            }
+            Some(latest) => {
+                let offset = latest - nonce;
+
+                if offset >= NONCE_WINDOW {
+                    return Err(format!("nonce {nonce} too old").into());
+                }
+
+                if self.nonce_bits & (1 << offset) != 0 {
+                    return Err(format!("nonce {nonce} replayed").into());
+                }
+
+                self.nonce_bits |= 1 << offset;
+            }
+            None => {
+                self.nonce_bits = 1;
+
+                self.latest_nonce = Some(nonce);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn session_id_of(datagram: &[u8]) -> Result<u32, Box<dyn Error + Sync + Send>> {
+    match datagram.get(..4) {
+        Some(bytes) => Ok(u32::from_le_bytes(bytes.try_into()?)),
+        None => Err(format!("datagram too short to open, {datagram:?}").into()),
+    }
+}
+
+/// The session a resume token claims to belong to, see
+/// [`RESUME_TOKEN_LEN`].
+pub fn resume_session_id(token: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(token.get(..4)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seal(session: &Session, nonce: u64, body: &[u8]) -> Vec<u8> {
+        let mut datagram = session.session_id.to_le_bytes().to_vec();
+
+        datagram.extend_from_slice(&nonce.to_le_bytes());
+
+        datagram.extend_from_slice(body);
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(session.key()).unwrap();
+
+        mac.update(&datagram);
+
+        datagram.extend_from_slice(&mac.finalize().into_bytes()[..MAC_LEN]);
+
+        datagram
+    }
+
+    fn session() -> Session {
+        Session::new(String::from("alice"), 7, 1, 0)
+    }
+
+    #[test]
+    fn valid_datagram_opens() {
+        let mut session = session();
+
+        let datagram = seal(&session, 1, b"body");
+
+        assert_eq!(session_id_of(&datagram).unwrap(), 7);
+
+        assert_eq!(session.open(&datagram).unwrap(), (&b"body"[..], true));
+    }
+
+    #[test]
+    fn tampered_body_or_mac_is_rejected() {
+        let mut session = session();
+
+        let datagram = seal(&session, 1, b"body");
+
+        for i in [4, 12, datagram.len() - 1] {
+            let mut tampered = datagram.clone();
+
+            tampered[i] ^= 1;
+
+            assert!(session.open(&tampered).is_err(), "byte {i} flipped");
+        }
+
+        assert!(session.open(&datagram).is_ok());
+    }
+
+    #[test]
+    fn datagram_from_another_session_is_rejected() {
+        let mut session = session();
+
+        let datagram = seal(&self::session(), 1, b"body");
+
+        assert!(session.open(&datagram).is_err());
+    }
+
+    #[test]
+    fn short_datagram_is_rejected() {
+        let mut session = session();
+
+        let datagram = seal(&session, 1, b"");
+
+        assert!(session.open(&datagram[..12 + MAC_LEN - 1]).is_err());
+
+        assert!(session.open(&datagram).is_ok());
+    }
+
+    #[test]
+    fn replayed_nonce_is_rejected() {
+        let mut session = session();
+
+        let datagram = seal(&session, 5, b"body");
+
+        assert!(session.open(&datagram).is_ok());
+
+        assert!(session.open(&datagram).is_err());
+    }
+
+    #[test]
+    fn out_of_order_nonce_in_window_is_accepted_once() {
+        let mut session = session();
+
+        assert!(session.open(&seal(&session, 100, b"")).is_ok());
+
+        let late = seal(&session, 100 - (NONCE_WINDOW - 1), b"");
+
+        assert!(!session.open(&late).unwrap().1);
+
+        assert!(session.open(&late).is_err());
+    }
+
+    #[test]
+    fn nonce_older_than_window_is_rejected() {
+        let mut session = session();
+
+        assert!(session.open(&seal(&session, NONCE_WINDOW, b"")).is_ok());
+
+        assert!(session.open(&seal(&session, 0, b"")).is_err());
+    }
+
+    #[test]
+    fn window_slides_with_newer_nonces() {
+        let mut session = session();
+
+        assert!(session.open(&seal(&session, 1, b"")).is_ok());
+
+        assert!(
+            session
+                .open(&seal(&session, 1 + NONCE_WINDOW, b""))
+                .unwrap()
+                .1
+        );
+
+        assert!(session.open(&seal(&session, 2, b"")).is_ok());
+
+        assert!(session.open(&seal(&session, 1, b"")).is_err());
+    }
+
+    #[test]
+    fn resume_token_names_its_session() {
+        let session = session();
+
+        let token = session.resume_token();
+
+        assert_eq!(token.len(), RESUME_TOKEN_LEN);
+
+        assert_eq!(resume_session_id(&token), Some(session.session_id));
+
+        assert!(session.verify_resume_token(&token));
+    }
+
+    #[test]
+    fn forged_resume_tokens_are_rejected() {
+        let session = session();
+
+        let token = session.resume_token();
+
+        let mut secret = token.clone();
+
+        secret[RESUME_TOKEN_LEN - 1] ^= 1;
+
+        assert!(!session.verify_resume_token(&secret));
+
+        let mut other = token.clone();
+
+        other[0] ^= 1;
+
+        assert!(!session.verify_resume_token(&other));
+
+        assert!(!session.verify_resume_token(&token[..RESUME_TOKEN_LEN - 1]));
+
+        assert!(!session.verify_resume_token(&[]));
+    }
+
+    #[test]
+    fn rotated_resume_token_replaces_the_old_one() {
+        let mut session = session();
+
+        let old = session.resume_token();
+
+        session.rotate_resume_token();
+
+        assert!(!session.verify_resume_token(&old));
+
+        assert!(session.verify_resume_token(&session.resume_token()));
+    }
+}
@@ -0,0 +1,179 @@
+use std::{collections::HashSet, io, time::Duration};
+
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::time;
+
+use crate::{
+    job::Job, job_handler, outgoing_packet::Outgoing, protocol::DisconnectReason, Context,
+};
+
+/// The signals that ask the server to shut down, SIGINT and SIGTERM.
+///
+/// Other platforms have no SIGTERM and only listen for Ctrl-C.
+pub struct Signals {
+    #[cfg(unix)]
+    interrupt: Signal,
+    #[cfg(unix)]
+    terminate: Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    pub fn new() -> io::Result<Self> {
+        Ok(Signals {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+        })
+    }
+
+    pub async fn recv(&mut self) {
+        tokio::select! {
+            _ = self.interrupt.recv() => {}
+            _ = self.terminate.recv() => {}
+        }
+    }
+}
+
+#[cfg(not(unix))]
+impl Signals {
+    pub fn new() -> io::Result<Self> {
+        Ok(Signals {})
+    }
+
+    pub async fn recv(&mut self) {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            eprintln!("failed to listen for ctrl-c for {e}");
+
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Says goodbye to every session and drains what is left of the loop.
+///
+/// The listeners are closed first and nothing sent by the clients is read
+/// anymore. The remaining scheduled jobs run right away, except for those
+/// that only keep the world running, and every outbound queue is flushed.
+/// Whatever is not done by `timeout` is lost.
+pub async fn drain(context: &mut Context, timeout: Duration) {
+    context.tcp_listener = None;
+
+    context.ws_listener = None;
+
+    context.incoming_receiver.close();
+
+    let drained = time::timeout(timeout, async {
+        let packet = Outgoing::Disconnect {
+            reason: DisconnectReason::Shutdown,
+            message: DisconnectReason::Shutdown.to_string(),
+        };
+
+        run(Job::BroadcastToTcp(packet, HashSet::new()), context).await;
+
+        while !context.schedule_queue.is_empty() {
+            for schedule in context.schedule_queue.drain() {
+                if schedule.job.survives_shutdown() && !schedule.is_recurring() {
+                    run(schedule.job, context).await;
+                }
+            }
+        }
+
+        run(Job::FlushToUdp, context).await;
+
+        let connections = context
+            .connections
+            .drain()
+            .map(|(_, connection)| connection);
+
+        futures::future::join_all(connections.map(|connection| connection.close())).await;
+    })
+    .await;
+
+    if drained.is_err() {
+        eprintln!("shutdown timed out after {timeout:?}");
+    }
+}
+
+async fn run(job: Job, context: &mut Context) {
+    if let Err(e) = job_handler::handle(job, context).await {
+        eprintln!("job failed for {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::*;
+    use crate::{incoming_packet::Incoming, testing};
+
+    #[tokio::test]
+    async fn drain_says_goodbye_and_stops_listening() {
+        let mut context = testing::context().await;
+
+        let addr = context.tcp_listener.as_ref().unwrap().local_addr().unwrap();
+
+        let mut client = testing::login(&mut context, "alice-token", 0).await;
+
+        client.received().await;
+
+        drain(&mut context, Duration::from_secs(1)).await;
+
+        let received = client.received().await;
+
+        assert!(matches!(
+            received[..],
+            [Outgoing::Disconnect {
+                reason: DisconnectReason::Shutdown,
+                ..
+            }]
+        ));
+
+        assert!(client.is_closed());
+
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn drain_does_not_wait_on_a_blocked_websocket() {
+        let mut context = testing::context().await;
+
+        let addr = context.ws_listener.as_ref().unwrap().local_addr().unwrap();
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+
+        let (accepted, peer) = context
+            .ws_listener
+            .as_ref()
+            .unwrap()
+            .accept()
+            .await
+            .unwrap();
+
+        job_handler::handle(Job::AcceptFromWebSocket(accepted, peer), &mut context)
+            .await
+            .unwrap();
+
+        let url = format!("ws://{addr}");
+
+        let (mut socket, _) = tokio_tungstenite::client_async(url, stream).await.unwrap();
+
+        let pong = Incoming::Pong { sequence: 0 }.serilaize().unwrap();
+
+        // Nothing reads the packets, so the bridge ends up waiting for room.
+        for _ in 0..2048 {
+            socket.send(Message::Binary(pong.clone())).await.unwrap();
+        }
+
+        time::sleep(Duration::from_millis(100)).await;
+
+        let started = time::Instant::now();
+
+        drain(&mut context, Duration::from_secs(2)).await;
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,402 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+};
+
+use crate::{math::Vector3, protocol::Wire};
+
+const HISTORY_LEN: usize = 32;
+
+const ORIGIN_CHANGED: u8 = 1 << 0;
+
+const ROTATION_CHANGED: u8 = 1 << 1;
+
+/// What `Outgoing::Snapshot` takes besides its entities and removed ids.
+pub const SNAPSHOT_OVERHEAD: usize = 2 + 4 + 4 + 2 + 2 + 2 + 2;
+
+/// The entities that changed and the ids removed since a base snapshot.
+pub type Delta = (Vec<EntityDelta>, Vec<String>);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub origin: Vector3,
+    pub rotation: f32,
+}
+
+/// The latest transform of every player at a tick.
+pub struct Snapshot {
+    pub tick: u32,
+    pub transforms: HashMap<String, Transform>,
+}
+
+impl Snapshot {
+    /// Lists what changed since `base`, or every transform without a base.
+    pub fn delta(&self, base: Option<&Snapshot>) -> Delta {
+        let empty = HashMap::new();
+
+        let base = base.map(|base| &base.transforms).unwrap_or(&empty);
+
+        let entities = self
+            .transforms
+            .iter()
+            .filter_map(|(id, transform)| {
+                let previous = base.get(id);
+
+                let origin = Some(transform.origin)
+                    .filter(|origin| previous.map(|p| &p.origin) != Some(origin));
+
+                let rotation = Some(transform.rotation)
+                    .filter(|rotation| previous.map(|p| &p.rotation) != Some(rotation));
+
+                if origin.is_none() && rotation.is_none() {
+                    return None;
+                }
+
+                Some(EntityDelta {
+                    id: id.clone(),
+                    origin,
+                    rotation,
+                })
+            })
+            .collect();
+
+        let removed = base
+            .keys()
+            .filter(|id| !self.transforms.contains_key(*id))
+            .cloned()
+            .collect();
+
+        (entities, removed)
+    }
+}
+
+/// Splits a delta into parts that each encode to at most `budget` bytes of
+/// entities and removed ids, so a crowded snapshot still fits a datagram.
+///
+/// Every part is a delta against the same base, and a client should only
+/// acknowledge a tick once all of its parts arrived.
+pub fn split(
+    entities: Vec<EntityDelta>,
+    removed: Vec<String>,
+    budget: usize,
+) -> Result<Vec<Delta>, Box<dyn Error + Sync + Send>> {
+    let mut parts = vec![(Vec::new(), Vec::new())];
+
+    let mut size = 0;
+
+    for entity in entities {
+        let len = encoded_len(&entity)?;
+
+        if size > 0 && size + len > budget {
+            parts.push((Vec::new(), Vec::new()));
+
+            size = 0;
+        }
+
+        size += len;
+
+        parts.last_mut().unwrap().0.push(entity);
+    }
+
+    for id in removed {
+        let len = encoded_len(&id)?;
+
+        if size > 0 && size + len > budget {
+            parts.push((Vec::new(), Vec::new()));
+
+            size = 0;
+        }
+
+        size += len;
+
+        parts.last_mut().unwrap().1.push(id);
+    }
+
+    Ok(parts)
+}
+
+fn encoded_len<T: Wire>(value: &T) -> Result<usize, Box<dyn Error + Sync + Send>> {
+    let mut buf = Vec::new();
+
+    value.encode(&mut buf)?;
+
+    Ok(buf.len())
+}
+
+/// The last snapshots sent, kept so clients can be sent deltas against the
+/// one they acknowledged.
+pub struct SnapshotHistory {
+    snapshots: VecDeque<Snapshot>,
+}
+
+impl SnapshotHistory {
+    pub fn new() -> Self {
+        SnapshotHistory {
+            snapshots: VecDeque::with_capacity(HISTORY_LEN),
+        }
+    }
+
+    pub fn push(&mut self, snapshot: Snapshot) {
+        if self.snapshots.len() == HISTORY_LEN {
+            self.snapshots.pop_front();
+        }
+
+        self.snapshots.push_back(snapshot);
+    }
+
+    pub fn get(&self, tick: u32) -> Option<&Snapshot> {
+        self.snapshots.iter().find(|snapshot| snapshot.tick == tick)
+    }
+}
+
+/// A player's transform fields that changed since the base snapshot.
+///
+/// Encoded as the id, a bitmask of the changed fields, then only the
+/// fields present in the mask.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct EntityDelta {
+    pub id: String,
+    pub origin: Option<Vector3>,
+    pub rotation: Option<f32>,
+}
+
+impl Wire for EntityDelta {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Box<dyn Error + Sync + Send>> {
+        self.id.encode(buf)?;
+
+        let mut mask = 0;
+
+        if self.origin.is_some() {
+            mask |= ORIGIN_CHANGED;
+        }
+
+        if self.rotation.is_some() {
+            mask |= ROTATION_CHANGED;
+        }
+
+        mask.encode(buf)?;
+
+        if let Some(origin) = &self.origin {
+            origin.encode(buf)?;
+        }
+
+        if let Some(rotation) = &self.rotation {
+            rotation.encode(buf)?;
+        }
+
+        Ok(())
+    }
+
+    fn decode(buf: &mut &[u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let id = String::decode(buf)?;
+
+        let mask = u8::decode(buf)?;
+
+        let origin = match mask & ORIGIN_CHANGED {
+            0 => None,
+            _ => Some(Vector3::decode(buf)?),
+        };
+
+        let rotation = match mask & ROTATION_CHANGED {
+            0 => None,
+            _ => Some(f32::decode(buf)?),
+        };
+
+        Ok(EntityDelta {
+            id,
+            origin,
+            rotation,
+        })
+    }
+}
+
+#[cfg(test)]
+impl crate::protocol::Sample for EntityDelta {
+    fn sample(seed: u8) -> Self {
+        EntityDelta {
+            id: format!("entity-{seed}"),
+            origin: seed
+                .is_multiple_of(2)
+                .then(|| Vector3::new(f32::from(seed), 1.0, 2.0)),
+            rotation: Some(f32::from(seed) + 0.5),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform(x: f32, rotation: f32) -> Transform {
+        Transform {
+            origin: Vector3::new(x, 0.0, 0.0),
+            rotation,
+        }
+    }
+
+    fn snapshot(tick: u32, transforms: &[(&str, Transform)]) -> Snapshot {
+        Snapshot {
+            tick,
+            transforms: transforms
+                .iter()
+                .map(|(id, transform)| (id.to_string(), *transform))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn delta_without_base_lists_everything() {
+        let current = snapshot(2, &[("a", transform(1.0, 0.5))]);
+
+        let (entities, removed) = current.delta(None);
+
+        assert_eq!(
+            entities,
+            vec![EntityDelta {
+                id: String::from("a"),
+                origin: Some(Vector3::new(1.0, 0.0, 0.0)),
+                rotation: Some(0.5),
+            }]
+        );
+
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn delta_lists_added_changed_and_removed_only() {
+        let base = snapshot(
+            1,
+            &[
+                ("unchanged", transform(1.0, 1.0)),
+                ("moved", transform(1.0, 1.0)),
+                ("turned", transform(1.0, 1.0)),
+                ("left", transform(1.0, 1.0)),
+            ],
+        );
+
+        let current = snapshot(
+            2,
+            &[
+                ("unchanged", transform(1.0, 1.0)),
+                ("moved", transform(2.0, 1.0)),
+                ("turned", transform(1.0, 2.0)),
+                ("joined", transform(3.0, 3.0)),
+            ],
+        );
+
+        let (mut entities, removed) = current.delta(Some(&base));
+
+        entities.sort_by(|a, b| a.id.cmp(&b.id));
+
+        assert_eq!(
+            entities,
+            vec![
+                EntityDelta {
+                    id: String::from("joined"),
+                    origin: Some(Vector3::new(3.0, 0.0, 0.0)),
+                    rotation: Some(3.0),
+                },
+                EntityDelta {
+                    id: String::from("moved"),
+                    origin: Some(Vector3::new(2.0, 0.0, 0.0)),
+                    rotation: None,
+                },
+                EntityDelta {
+                    id: String::from("turned"),
+                    origin: None,
+                    rotation: Some(2.0),
+                },
+            ]
+        );
+
+        assert_eq!(removed, vec![String::from("left")]);
+    }
+
+    #[test]
+    fn entity_delta_encodes_only_changed_fields() {
+        let cases = [
+            (None, None, 0),
+            (Some(Vector3::new(1.0, 2.0, 3.0)), None, 12),
+            (None, Some(0.5), 4),
+            (Some(Vector3::new(1.0, 2.0, 3.0)), Some(0.5), 16),
+        ];
+
+        for (origin, rotation, fields_len) in cases {
+            let delta = EntityDelta {
+                id: String::from("a"),
+                origin,
+                rotation,
+            };
+
+            let mut buf = Vec::new();
+
+            delta.encode(&mut buf).unwrap();
+
+            assert_eq!(buf.len(), 2 + 1 + 1 + fields_len);
+
+            let mut body = buf.as_slice();
+
+            assert_eq!(EntityDelta::decode(&mut body).unwrap(), delta);
+
+            assert!(body.is_empty());
+        }
+    }
+
+    #[test]
+    fn history_forgets_the_oldest() {
+        let mut history = SnapshotHistory::new();
+
+        for tick in 0..HISTORY_LEN as u32 + 1 {
+            history.push(snapshot(tick, &[]));
+        }
+
+        assert!(history.get(0).is_none());
+
+        assert!(history.get(1).is_some());
+
+        assert!(history.get(HISTORY_LEN as u32).is_some());
+    }
+
+    #[test]
+    fn split_keeps_every_part_within_budget() {
+        let entities: Vec<_> = (0..100)
+            .map(|i| EntityDelta {
+                id: format!("player-{i}"),
+                origin: Some(Vector3::new(i as f32, 0.0, 0.0)),
+                rotation: Some(0.0),
+            })
+            .collect();
+
+        let removed: Vec<_> = (0..50).map(|i| format!("gone-{i}")).collect();
+
+        let parts = split(entities.clone(), removed.clone(), 256).unwrap();
+
+        assert!(parts.len() > 1);
+
+        for (entities, removed) in &parts {
+            let len: usize = entities
+                .iter()
+                .map(|e| encoded_len(e).unwrap())
+                .sum::<usize>()
+                + removed
+                    .iter()
+                    .map(|id| encoded_len(id).unwrap())
+                    .sum::<usize>();
+
+            assert!(len <= 256);
+        }
+
+        let (all_entities, all_removed): (Vec<_>, Vec<_>) = parts.into_iter().unzip();
+
+        assert_eq!(all_entities.concat(), entities);
+
+        assert_eq!(all_removed.concat(), removed);
+    }
+
+    #[test]
+    fn split_of_a_small_delta_is_one_part() {
+        let parts = split(Vec::new(), Vec::new(), 256).unwrap();
+
+        assert_eq!(parts, vec![(Vec::new(), Vec::new())]);
+    }
+}
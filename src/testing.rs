@@ -0,0 +1,252 @@
+//! Drives a [`Context`] the way the main loop does, over real sockets bound to
+//! the loopback, so tests can play a client against it.
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time,
+};
+
+use crate::{
+    auth::MockAuthProvider,
+    config::ServerConfig,
+    incoming_packet::Incoming,
+    job::Job,
+    job_handler,
+    net::{wrap_tcp_packet, FrameDecoder},
+    outgoing_packet::Outgoing,
+    protocol::{unbatch, Wire},
+    reliable::{Delivery, Header},
+    selector,
+    session::MAC_LEN,
+    Context,
+};
+
+/// Tokens the mock provider of [`context`] knows.
+pub const TOKENS: [(&str, &str); 3] = [
+    ("alice-token", "alice"),
+    ("bob-token", "bob"),
+    ("carol-token", "carol"),
+];
+
+pub async fn context() -> Context {
+    context_with(ServerConfig::default()).await
+}
+
+pub async fn context_with(config: ServerConfig) -> Context {
+    let tokens = TOKENS
+        .iter()
+        .map(|(token, id)| (token.to_string(), id.to_string()))
+        .collect::<HashMap<_, _>>();
+
+    let provider = Arc::new(MockAuthProvider::new(tokens));
+
+    let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    let ws_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    let udp_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    Context::new(config, provider, tcp_listener, ws_listener, udp_socket)
+}
+
+/// Runs the main loop for `duration`.
+pub async fn run_for(context: &mut Context, duration: Duration) {
+    let deadline = time::Instant::now() + duration;
+
+    loop {
+        let job = tokio::select! {
+            job = selector::select_job(context) => job,
+            _ = time::sleep_until(deadline) => return,
+        };
+
+        if let Err(e) = job_handler::handle(job, context).await {
+            eprintln!("job failed for {e}");
+        }
+    }
+}
+
+/// Runs the main loop long enough for a round trip over the loopback.
+pub async fn settle(context: &mut Context) {
+    run_for(context, Duration::from_millis(100)).await;
+}
+
+/// Accepts a TCP client.
+pub async fn connect(context: &mut Context) -> Client {
+    let listener = context.tcp_listener.as_ref().unwrap();
+
+    let stream = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+
+    let (accepted, addr) = listener.accept().await.unwrap();
+
+    job_handler::handle(Job::AcceptFromTcp(accepted, addr), context)
+        .await
+        .unwrap();
+
+    Client::new(stream)
+}
+
+/// Connects and authenticates as the player of `token`.
+pub async fn login(context: &mut Context, token: &str, capabilities: u32) -> Client {
+    let mut client = connect(context).await;
+
+    client
+        .send(Incoming::TcpHello {
+            version: crate::protocol::PROTOCOL_VERSION,
+            capabilities,
+            token: token.to_owned(),
+        })
+        .await;
+
+    settle(context).await;
+
+    client
+}
+
+/// The client side of a TCP connection.
+pub struct Client {
+    stream: TcpStream,
+    decoder: FrameDecoder,
+    closed: bool,
+}
+
+impl Client {
+    pub fn new(stream: TcpStream) -> Self {
+        Client {
+            stream,
+            decoder: FrameDecoder::new(),
+            closed: false,
+        }
+    }
+
+    pub async fn send(&mut self, packet: Incoming) {
+        self.send_raw(&packet.serilaize().unwrap()).await;
+    }
+
+    /// Sends `packet` as is in a frame of its own.
+    pub async fn send_raw(&mut self, packet: &[u8]) {
+        let buf = wrap_tcp_packet(packet, false).unwrap();
+
+        self.stream.write_all(&buf).await.unwrap();
+    }
+
+    /// Every packet that arrived so far besides pings.
+    pub async fn received(&mut self) -> Vec<Outgoing> {
+        let mut buf = vec![0; 64 * 1024];
+
+        while !self.closed {
+            match time::timeout(Duration::from_millis(20), self.stream.read(&mut buf)).await {
+                Ok(Ok(0)) | Ok(Err(_)) => self.closed = true,
+                Ok(Ok(n)) => self.decoder.extend(&buf[..n]),
+                Err(_) => break,
+            }
+        }
+
+        let mut packets = Vec::new();
+
+        while let Some(frame) = self.decoder.next_frame() {
+            let packet = Outgoing::deserialize(&frame.unwrap()).unwrap();
+
+            if !matches!(packet, Outgoing::Ping { .. }) {
+                packets.push(packet);
+            }
+        }
+
+        packets
+    }
+
+    /// Whether the server closed the connection, as of the last
+    /// [`Client::received`].
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+/// The client side of a session's UDP traffic, sealing every datagram with
+/// the secret of its `HelloFromTcp`.
+pub struct UdpClient {
+    pub socket: UdpSocket,
+    server: SocketAddr,
+    session_id: u32,
+    key: Vec<u8>,
+}
+
+impl UdpClient {
+    pub async fn new(context: &Context, hello: &Outgoing) -> Self {
+        let Outgoing::HelloFromTcp {
+            session_id, secret, ..
+        } = hello
+        else {
+            panic!("expected HelloFromTcp, got {hello:?}");
+        };
+
+        UdpClient {
+            socket: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            server: context.udp_socket.local_addr().unwrap(),
+            session_id: *session_id,
+            key: secret.clone(),
+        }
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.socket.local_addr().unwrap()
+    }
+
+    pub fn session_id(&self) -> u32 {
+        self.session_id
+    }
+
+    /// Sends `packet` unreliably in a datagram carrying `nonce`.
+    pub async fn send(&self, nonce: u64, packet: Incoming) {
+        let mut datagram = self.session_id.to_le_bytes().to_vec();
+
+        datagram.extend_from_slice(&nonce.to_le_bytes());
+
+        let header = Header {
+            delivery: Delivery::Unreliable,
+            sequence: 0,
+            ack: 0,
+            ack_bits: 0,
+        };
+
+        header.encode(&mut datagram).unwrap();
+
+        datagram.extend_from_slice(&packet.serilaize().unwrap());
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+
+        mac.update(&datagram);
+
+        datagram.extend_from_slice(&mac.finalize().into_bytes()[..MAC_LEN]);
+
+        self.socket.send_to(&datagram, self.server).await.unwrap();
+    }
+
+    /// Every packet that arrived so far, pings included.
+    pub async fn received(&self) -> Vec<Outgoing> {
+        let mut buf = vec![0; 64 * 1024];
+
+        let mut packets = Vec::new();
+
+        while let Ok(Ok(n)) =
+            time::timeout(Duration::from_millis(20), self.socket.recv(&mut buf)).await
+        {
+            for mut message in unbatch(&buf[..n]).unwrap() {
+                Header::decode(&mut message).unwrap();
+
+                if !message.is_empty() {
+                    packets.push(Outgoing::deserialize(message).unwrap());
+                }
+            }
+        }
+
+        packets
+    }
+}
@@ -0,0 +1,183 @@
+use std::time::Duration;
+
+use crate::Context;
+
+pub const DEFAULT_TICK_RATE: u32 = 20;
+
+/// How many missed ticks are simulated at once before the tick gives up on
+/// catching up and skips the rest.
+pub const MAX_CATCH_UP: u32 = 5;
+
+pub struct Tick {
+    pub number: u32,
+    pub delta: Duration,
+}
+
+pub type TickHook = fn(&mut Context, &Tick);
+
+/// Game logic run by every `Job::Tick`, phase by phase.
+///
+/// `timers` and `simulation` run once per tick, including ticks being
+/// caught up after the loop fell behind. `snapshots` run once per `Job::Tick`
+/// with the latest tick, after the world has been brought up to date.
+#[derive(Default, Clone)]
+pub struct TickHooks {
+    pub timers: Vec<TickHook>,
+    pub simulation: Vec<TickHook>,
+    pub snapshots: Vec<TickHook>,
+}
+
+pub fn interval(rate: u32) -> Duration {
+    Duration::from_secs(1) / rate.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use tokio::time;
+
+    use super::*;
+    use crate::{job::Job, job_handler, testing};
+
+    thread_local! {
+        /// Every hook run on this thread, by phase and tick number.
+        static RUNS: RefCell<Vec<(&'static str, u32)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record(phase: &'static str, tick: &Tick) {
+        RUNS.with(|runs| runs.borrow_mut().push((phase, tick.number)));
+    }
+
+    fn runs(phase: &str) -> Vec<u32> {
+        RUNS.with(|runs| {
+            runs.borrow()
+                .iter()
+                .filter(|(p, _)| *p == phase)
+                .map(|(_, number)| *number)
+                .collect()
+        })
+    }
+
+    /// A context whose only tick hooks record their runs, with no tick
+    /// scheduled yet.
+    async fn context() -> Context {
+        let mut context = testing::context().await;
+
+        context.schedule_queue.drain();
+
+        context.tick_hooks = TickHooks {
+            timers: vec![|_, tick| record("timers", tick)],
+            simulation: vec![|_, tick| record("simulation", tick)],
+            snapshots: vec![|_, tick| record("snapshots", tick)],
+        };
+
+        context
+    }
+
+    /// Runs the tick due at `deadline` and returns the deadline of the next.
+    async fn tick(context: &mut Context, deadline: time::Instant) -> time::Instant {
+        job_handler::handle(Job::Tick(deadline), context)
+            .await
+            .unwrap();
+
+        let next = context
+            .schedule_queue
+            .drain()
+            .into_iter()
+            .filter_map(|schedule| match schedule.job {
+                Job::Tick(next) => Some((next, schedule.deadline)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(next.len(), 1);
+
+        assert_eq!(next[0].0, next[0].1);
+
+        next[0].0
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tick_on_time_runs_every_phase_once() {
+        let mut context = context().await;
+
+        let delta = interval(context.config.tick_rate);
+
+        let deadline = time::Instant::now();
+
+        assert_eq!(tick(&mut context, deadline).await, deadline + delta);
+
+        assert_eq!(runs("timers"), [1]);
+
+        assert_eq!(runs("simulation"), [1]);
+
+        assert_eq!(runs("snapshots"), [1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn late_tick_catches_up_and_stays_on_schedule() {
+        let mut context = context().await;
+
+        let delta = interval(context.config.tick_rate);
+
+        let deadline = time::Instant::now();
+
+        time::advance(delta * 2 + delta / 2).await;
+
+        assert_eq!(tick(&mut context, deadline).await, deadline + delta * 3);
+
+        assert_eq!(runs("timers"), [1, 2, 3]);
+
+        assert_eq!(runs("simulation"), [1, 2, 3]);
+
+        assert_eq!(runs("snapshots"), [3]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn catching_up_stops_at_the_cap() {
+        let mut context = context().await;
+
+        let delta = interval(context.config.tick_rate);
+
+        let deadline = time::Instant::now();
+
+        time::advance(delta * MAX_CATCH_UP - delta / 2).await;
+
+        let next = tick(&mut context, deadline).await;
+
+        assert_eq!(next, deadline + delta * MAX_CATCH_UP);
+
+        assert_eq!(runs("simulation"), [1, 2, 3, 4, 5]);
+
+        assert_eq!(runs("snapshots"), [5]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tick_too_far_behind_skips_ahead() {
+        let mut context = context().await;
+
+        let delta = interval(context.config.tick_rate);
+
+        let deadline = time::Instant::now();
+
+        time::advance(delta * (MAX_CATCH_UP + 3)).await;
+
+        let next = tick(&mut context, deadline).await;
+
+        assert_eq!(next, time::Instant::now() + delta);
+
+        assert_eq!(runs("timers").len(), MAX_CATCH_UP as usize);
+
+        assert_eq!(runs("simulation").len(), MAX_CATCH_UP as usize);
+
+        assert_eq!(runs("snapshots"), [MAX_CATCH_UP]);
+
+        // The next tick after the skip is on time again.
+        time::advance(delta).await;
+
+        assert_eq!(tick(&mut context, next).await, next + delta);
+
+        assert_eq!(runs("snapshots"), [MAX_CATCH_UP, MAX_CATCH_UP + 1]);
+    }
+}
@@ -0,0 +1,95 @@
+use std::{
+    error::Error,
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use futures::{Sink, SinkExt, StreamExt};
+use tokio::{net::TcpStream, sync::mpsc};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+use crate::{
+    connection::{ConnectionId, Inbound},
+    incoming_packet::Incoming,
+};
+
+/// Owns a WebSocket connection for its whole life.
+///
+/// The task performs the handshake, forwards every binary message as one
+/// decoded packet and sends every packet of the outbound queue as one binary
+/// message, so the game loop treats it like a TCP connection. While the game
+/// loop has no room for another packet, the task stops reading but keeps
+/// flushing. Once the game loop stops reading, as it does during shutdown,
+/// the task only flushes the outbound queue.
+pub async fn bridge(
+    id: ConnectionId,
+    stream: TcpStream,
+    inbound: mpsc::Sender<Inbound>,
+    outbound: mpsc::UnboundedReceiver<Vec<u8>>,
+    queued: Arc<AtomicUsize>,
+) {
+    let e = match pump(id, stream, &inbound, outbound, queued).await {
+        Ok(_) => io::Error::from(io::ErrorKind::UnexpectedEof).into(),
+        Err(e) => e,
+    };
+
+    let _ = inbound.send((id, Err(e))).await;
+}
+
+async fn pump(
+    id: ConnectionId,
+    stream: TcpStream,
+    inbound: &mpsc::Sender<Inbound>,
+    mut outbound: mpsc::UnboundedReceiver<Vec<u8>>,
+    queued: Arc<AtomicUsize>,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let (mut sink, mut source) = tokio_tungstenite::accept_async(stream).await?.split();
+
+    let mut pending = None;
+
+    loop {
+        tokio::select! {
+            permit = inbound.reserve(), if pending.is_some() => match permit {
+                Ok(permit) => permit.send((id, Ok(pending.take().unwrap()))),
+                Err(_) => break,
+            },
+            message = source.next(), if pending.is_none() => match message {
+                Some(Ok(Message::Binary(buf))) => pending = Some(Incoming::deserialize(&buf)?),
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+            },
+            packet = outbound.recv() => match packet {
+                Some(packet) => forward(packet, &mut sink, &queued).await?,
+                None => return Ok(()),
+            },
+        }
+    }
+
+    while let Some(packet) = outbound.recv().await {
+        forward(packet, &mut sink, &queued).await?;
+    }
+
+    Ok(())
+}
+
+/// Sends `packet` as one binary message.
+async fn forward<S>(
+    packet: Vec<u8>,
+    sink: &mut S,
+    queued: &AtomicUsize,
+) -> Result<(), Box<dyn Error + Sync + Send>>
+where
+    S: Sink<Message, Error = WsError> + Unpin,
+{
+    let len = packet.len();
+
+    sink.send(Message::Binary(packet)).await?;
+
+    queued.fetch_sub(len, Ordering::Relaxed);
+
+    Ok(())
+}